@@ -0,0 +1,223 @@
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+
+// what to do with a record whose field can't be evaluated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum OnError {
+    #[default]
+    Keep,
+    Blank,
+    Abort,
+}
+
+pub(crate) const NOT_A_NUMBER: &str = "field is not a number";
+pub(crate) const MISSING_FIELD: &str = "record has no such field";
+
+/// Which field of each record to replace, set by `--field`, `--delimiter`
+/// and `--on-error`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub(crate) struct Fields {
+    pub column: Option<usize>,
+    pub delimiter: Option<String>,
+    pub on_error: OnError,
+}
+
+impl Fields {
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), &'static str> {
+        match (name, value) {
+            ("field", column) => match column.parse() {
+                Ok(column) if column > 0 => self.column = Some(column),
+                _ => return Err("usage: --field <column, counting from 1>"),
+            },
+            ("delimiter", "") => return Err("usage: --delimiter <text>"),
+            ("delimiter", delimiter) => self.delimiter = Some(delimiter.to_string()),
+            ("on-error", "keep") => self.on_error = OnError::Keep,
+            ("on-error", "blank") => self.on_error = OnError::Blank,
+            ("on-error", "abort") => self.on_error = OnError::Abort,
+            ("on-error", _) => return Err("usage: --on-error {keep|blank|abort}"),
+            _ => return Err("unknown setting"),
+        }
+        Ok(())
+    }
+
+    // the byte range of the field in `record`; without a delimiter fields are
+    // separated by any run of whitespace
+    fn range(&self, column: usize, record: &str) -> Option<Range<usize>> {
+        match &self.delimiter {
+            Some(delimiter) => {
+                let mut start = 0;
+                for _ in 1..column {
+                    start += record[start..].find(delimiter.as_str())? + delimiter.len();
+                }
+                let end = record[start..]
+                    .find(delimiter.as_str())
+                    .map_or(record.len(), |i| start + i);
+                Some(start..end)
+            }
+            None => {
+                let mut start = 0;
+                for n in 1..=column {
+                    let rest = record[start..].trim_start();
+                    if rest.is_empty() {
+                        return None;
+                    }
+                    start = record.len() - rest.len();
+                    let end = rest
+                        .find(char::is_whitespace)
+                        .map_or(record.len(), |i| start + i);
+                    if n == column {
+                        return Some(start..end);
+                    }
+                    start = end;
+                }
+                None
+            }
+        }
+    }
+
+    fn replace(
+        &self,
+        column: usize,
+        record: &str,
+        eval: &mut impl FnMut(f64) -> Result<String, &'static str>,
+    ) -> Result<String, (Option<Range<usize>>, &'static str)> {
+        let range = self.range(column, record).ok_or((None, MISSING_FIELD))?;
+        let value = match record[range.clone()].trim().parse::<f64>() {
+            Ok(value) if value.is_finite() => value,
+            _ => return Err((Some(range), NOT_A_NUMBER)),
+        };
+        let result = eval(value).map_err(|msg| (Some(range.clone()), msg))?;
+        Ok(format!(
+            "{}{}{}",
+            &record[..range.start],
+            result,
+            &record[range.end..]
+        ))
+    }
+
+    /// Writes each record of `input` to `output` with the field replaced by
+    /// `eval` of its value. Records that fail are reported to `errors` by line
+    /// number and then kept, blanked or end the filter, which returns false.
+    pub fn filter(
+        &self,
+        input: impl BufRead,
+        mut output: impl Write,
+        mut errors: impl Write,
+        mut eval: impl FnMut(f64) -> Result<String, &'static str>,
+    ) -> io::Result<bool> {
+        let column = self.column.expect("filter needs a field");
+        for (i, record) in input.lines().enumerate() {
+            let record = record?;
+            let line = match self.replace(column, &record, &mut eval) {
+                Ok(line) => line,
+                Err((range, msg)) => {
+                    writeln!(errors, "line {}: {}", i + 1, msg)?;
+                    match (self.on_error, range) {
+                        (OnError::Abort, _) => return Ok(false),
+                        (OnError::Blank, Some(range)) => {
+                            format!("{}{}", &record[..range.start], &record[range.end..])
+                        }
+                        _ => record,
+                    }
+                }
+            };
+            writeln!(output, "{}", line)?;
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    fn fields(column: usize, delimiter: Option<&str>, on_error: OnError) -> Fields {
+        Fields {
+            column: Some(column),
+            delimiter: delimiter.map(str::to_string),
+            on_error,
+        }
+    }
+
+    fn filter(fields: &Fields, input: &str) -> (bool, String, String) {
+        let (mut output, mut errors) = (Vec::new(), Vec::new());
+        let ok = fields
+            .filter(input.as_bytes(), &mut output, &mut errors, |x| {
+                if x == 0.0 {
+                    Err("division by zero")
+                } else {
+                    Ok((10.0 / x).to_string())
+                }
+            })
+            .unwrap();
+        let text = |bytes| String::from_utf8(bytes).unwrap();
+        (ok, text(output), text(errors))
+    }
+
+    #[test]
+    pub fn whitespace_fields_keep_their_spacing() {
+        let fields = fields(2, None, OnError::Keep);
+        assert_eq!(Some(4..5), fields.range(2, "a   4  b"));
+        assert_eq!(Some(3..4), fields.range(2, " a 4"));
+        assert_eq!(None, fields.range(3, "a 4  "));
+        assert_eq!(
+            (true, "a   2.5  b\n".to_string(), String::new()),
+            filter(&fields, "a   4  b\n")
+        );
+    }
+
+    #[test]
+    pub fn delimited_fields_may_be_empty() {
+        let fields = fields(3, Some(", "), OnError::Keep);
+        assert_eq!(Some(6..6), fields.range(3, "a, b, , c"));
+        assert_eq!(Some(6..9), fields.range(3, "a, b, 2.5"));
+        assert_eq!(None, fields.range(4, "a, b, 2.5"));
+        assert_eq!(
+            (true, "a, b, 4, c\n".to_string(), String::new()),
+            filter(&fields, "a, b, 2.5, c")
+        );
+    }
+
+    #[test]
+    pub fn failed_records_follow_the_error_policy() {
+        let input = "a 5\nb x\nc 0\nd\ne 2\n";
+        let errors = "line 2: field is not a number\nline 3: division by zero\n\
+                      line 4: record has no such field\n";
+        assert_eq!(
+            (
+                true,
+                "a 2\nb x\nc 0\nd\ne 5\n".to_string(),
+                errors.to_string()
+            ),
+            filter(&fields(2, None, OnError::Keep), input)
+        );
+        assert_eq!(
+            (
+                true,
+                "a 2\nb \nc \nd\ne 5\n".to_string(),
+                errors.to_string()
+            ),
+            filter(&fields(2, None, OnError::Blank), input)
+        );
+        assert_eq!(
+            (
+                false,
+                "a 2\n".to_string(),
+                "line 2: field is not a number\n".to_string()
+            ),
+            filter(&fields(2, None, OnError::Abort), input)
+        );
+    }
+
+    #[test]
+    pub fn settings_are_checked() {
+        let mut fields = Fields::default();
+        assert!(fields.set("field", "0").is_err());
+        assert!(fields.set("delimiter", "").is_err());
+        assert!(fields.set("on-error", "ignore").is_err());
+        fields.set("field", "3").unwrap();
+        fields.set("delimiter", ";").unwrap();
+        fields.set("on-error", "abort").unwrap();
+        assert_eq!(self::fields(3, Some(";"), OnError::Abort), fields);
+    }
+}
@@ -0,0 +1,522 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum Radix {
+    #[default]
+    Dec,
+    Hex,
+    Oct,
+    Bin,
+}
+
+impl Radix {
+    // the names used by the :hex style commands and `--base`
+    pub fn from_name(name: &str) -> Option<Radix> {
+        match name {
+            "dec" => Some(Radix::Dec),
+            "hex" => Some(Radix::Hex),
+            "oct" => Some(Radix::Oct),
+            "bin" => Some(Radix::Bin),
+            _ => None,
+        }
+    }
+
+    fn not_an_integer(self) -> &'static str {
+        match self {
+            Radix::Hex => "cannot display non-integer in base 16",
+            Radix::Oct => "cannot display non-integer in base 8",
+            Radix::Bin => "cannot display non-integer in base 2",
+            Radix::Dec => unreachable!("every number can be shown in decimal"),
+        }
+    }
+}
+
+pub(crate) const TOO_LARGE: &str = "too large to display in this base";
+
+// non-decimal results use the same 0x/0o/0b prefixes that are accepted as input
+pub(crate) fn format_radix(value: f64, radix: Radix) -> Result<String, &'static str> {
+    if radix == Radix::Dec {
+        return Ok(value.to_string());
+    }
+    if !value.is_finite() || value.fract() != 0.0 {
+        return Err(radix.not_an_integer());
+    }
+    if value.abs() >= 2f64.powi(127) {
+        return Err(TOO_LARGE);
+    }
+    let n = value as i128;
+    let sign = if n < 0 { "-" } else { "" };
+    let n = n.unsigned_abs();
+    Ok(match radix {
+        Radix::Hex => format!("{}0x{:x}", sign, n),
+        Radix::Oct => format!("{}0o{:o}", sign, n),
+        Radix::Bin => format!("{}0b{:b}", sign, n),
+        Radix::Dec => unreachable!(),
+    })
+}
+
+const MANTISSA_BITS: u32 = 52;
+const EXPONENT_BITS: u32 = 11;
+const EXPONENT_BIAS: i64 = 1023;
+
+// the sign, exponent and mantissa fields of the IEEE-754 double `value`,
+// in binary and separated by spaces
+pub(crate) fn bits(value: f64) -> String {
+    let n = value.to_bits();
+    let exponent = (n >> MANTISSA_BITS) & ((1 << EXPONENT_BITS) - 1);
+    let mantissa = n & ((1 << MANTISSA_BITS) - 1);
+    format!(
+        "{} {:0ew$b} {:0mw$b}",
+        n >> 63,
+        exponent,
+        mantissa,
+        ew = EXPONENT_BITS as usize,
+        mw = MANTISSA_BITS as usize
+    )
+}
+
+// `value` as a hex float like 0x1.91eb851eb851fp+1, the mantissa in hex and a
+// power of two, which can be read back exactly; subnormals keep the smallest
+// exponent and a leading 0 like C's %a
+pub(crate) fn hexfloat(value: f64) -> String {
+    if !value.is_finite() {
+        return value.to_string();
+    }
+    let n = value.to_bits();
+    let sign = if n >> 63 == 1 { "-" } else { "" };
+    let biased = ((n >> MANTISSA_BITS) & ((1 << EXPONENT_BITS) - 1)) as i64;
+    let mantissa = n & ((1 << MANTISSA_BITS) - 1);
+    let (lead, exponent) = match (biased, mantissa) {
+        (0, 0) => (0, 0),
+        (0, _) => (0, 1 - EXPONENT_BIAS),
+        _ => (1, biased - EXPONENT_BIAS),
+    };
+    let digits = format!("{:013x}", mantissa);
+    let digits = digits.trim_end_matches('0');
+    let point = if digits.is_empty() { "" } else { "." };
+    format!("{}0x{}{}{}p{:+}", sign, lead, point, digits, exponent)
+}
+
+pub(crate) const MAX_PRECISION: usize = 100;
+
+// magnitudes outside of these are shown in scientific notation, the upper one
+// being where whole numbers stop being exact
+const SCI_ABOVE: f64 = 1e16;
+const SCI_BELOW: f64 = 1e-6;
+
+// the shortest representation that reads back as the same value, so whole
+// numbers have no decimal point, unless a number of digits after the point is
+// given
+pub fn format_result(value: f64, precision: Option<usize>) -> String {
+    if value != 0.0 && !(SCI_BELOW..SCI_ABOVE).contains(&value.abs()) {
+        return format_sci(value, precision);
+    }
+    match precision {
+        Some(digits) => format!("{:.*}", digits, value),
+        None => value.to_string(),
+    }
+}
+
+// like `format_result` but in scientific notation whatever the magnitude, so
+// 1500 is "1.5e3"
+pub(crate) fn format_sci(value: f64, precision: Option<usize>) -> String {
+    match precision {
+        Some(digits) => format!("{:.*e}", digits, value),
+        None => format!("{:e}", value),
+    }
+}
+
+// writes the exponent of a decimal result with `E`, the only `e` it can have
+pub(crate) fn upper_exponent(text: &str) -> String {
+    text.replacen('e', "E", 1)
+}
+
+// from femto (1e-15) to tera (1e12), one for every power of a thousand
+const SI_PREFIXES: [&str; 10] = ["f", "p", "n", "µ", "m", "", "k", "M", "G", "T"];
+const SI_UNPREFIXED: i32 = 5;
+
+// the result scaled to between 1 and 1000 with an SI prefix, so 0.0047 is
+// "4.7m"; the scaling goes through the decimal digits so it adds no rounding
+// error, and values past the prefixes fall back to scientific notation
+pub(crate) fn format_si(value: f64, precision: Option<usize>) -> String {
+    if value == 0.0 || !value.is_finite() {
+        return format_result(value, precision);
+    }
+    let sci = format!("{:e}", value);
+    let (mantissa, exponent) = sci.split_once('e').expect("scientific has an exponent");
+    let exponent: i32 = exponent.parse().expect("exponent is an integer");
+    let group = exponent.div_euclid(3);
+    let index = group + SI_UNPREFIXED;
+    let prefix = match SI_PREFIXES.get(index as usize) {
+        Some(prefix) if index >= 0 => prefix,
+        _ => return format_sci(value, precision),
+    };
+    let scaled: f64 = format!("{}e{}", mantissa, exponent - 3 * group)
+        .parse()
+        .expect("scaled digits are a number");
+    match precision {
+        Some(digits) => format!("{:.*}{}", digits, scaled, prefix),
+        None => format!("{}{}", scaled, prefix),
+    }
+}
+
+// drops the zeros at the end of the digits after the point, and the point if
+// nothing is left after it, keeping any exponent or prefix that follows
+pub(crate) fn trim_zeros(text: &str) -> String {
+    let point = match text.find('.') {
+        Some(point) => point,
+        None => return text.to_string(),
+    };
+    let (whole, rest) = text.split_at(point);
+    let fraction = &rest[1..];
+    let digits = fraction
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(fraction.len());
+    let (fraction, suffix) = fraction.split_at(digits);
+    match fraction.trim_end_matches('0') {
+        "" => format!("{}{}", whole, suffix),
+        fraction => format!("{}.{}{}", whole, fraction, suffix),
+    }
+}
+
+pub(crate) const TOO_LARGE_FOR_WORDS: &str = "too large to say in words";
+pub(crate) const TOO_MANY_DECIMALS: &str = "too many decimal places to say in words";
+
+const WORDS_BELOW: f64 = 1e15;
+const MAX_SPOKEN_DECIMALS: usize = 6;
+
+const ONES: [&str; 20] = [
+    "zero",
+    "one",
+    "two",
+    "three",
+    "four",
+    "five",
+    "six",
+    "seven",
+    "eight",
+    "nine",
+    "ten",
+    "eleven",
+    "twelve",
+    "thirteen",
+    "fourteen",
+    "fifteen",
+    "sixteen",
+    "seventeen",
+    "eighteen",
+    "nineteen",
+];
+const TENS: [&str; 10] = [
+    "", "", "twenty", "thirty", "forty", "fifty", "sixty", "seventy", "eighty", "ninety",
+];
+const SCALES: [(u64, &str); 4] = [
+    (1_000_000_000_000, "trillion"),
+    (1_000_000_000, "billion"),
+    (1_000_000, "million"),
+    (1_000, "thousand"),
+];
+
+fn below_thousand(n: u64, words: &mut Vec<String>) {
+    let (hundreds, rest) = (n / 100, n % 100);
+    if hundreds > 0 {
+        words.push(format!("{} hundred", ONES[hundreds as usize]));
+    }
+    match rest {
+        0 if hundreds > 0 => {}
+        0..=19 => words.push(ONES[rest as usize].to_string()),
+        _ if rest % 10 == 0 => words.push(TENS[rest as usize / 10].to_string()),
+        _ => words.push(format!(
+            "{}-{}",
+            TENS[rest as usize / 10],
+            ONES[rest as usize % 10]
+        )),
+    }
+}
+
+// whole numbers in the short scale, so 1e9 is "one billion", and decimals read
+// digit by digit after "point", as in "three point one four"
+pub(crate) fn words(value: f64) -> Result<String, &'static str> {
+    if !value.is_finite() || value.abs() >= WORDS_BELOW {
+        return Err(TOO_LARGE_FOR_WORDS);
+    }
+    let digits = value.abs().to_string();
+    let (whole, decimals) = digits.split_once('.').unwrap_or((&digits, ""));
+    if decimals.len() > MAX_SPOKEN_DECIMALS {
+        return Err(TOO_MANY_DECIMALS);
+    }
+
+    let mut words = Vec::new();
+    if value < 0.0 {
+        words.push("minus".to_string());
+    }
+    let mut n: u64 = whole.parse().expect("whole part is digits");
+    for (scale, name) in SCALES.iter() {
+        if n >= *scale {
+            below_thousand(n / scale, &mut words);
+            words.push(name.to_string());
+            n %= scale;
+        }
+    }
+    if n > 0 || words.len() <= 1 {
+        below_thousand(n, &mut words);
+    }
+    if !decimals.is_empty() {
+        words.push("point".to_string());
+        for digit in decimals.chars() {
+            words.push(ONES[digit.to_digit(10).expect("decimals are digits") as usize].to_string());
+        }
+    }
+    Ok(words.join(" "))
+}
+
+const MAX_DENOMINATOR: u64 = 10_000;
+
+// the simplest fraction within rounding error of `value`, found from the
+// convergents of its continued fraction; whole numbers have no fraction
+pub(crate) fn fraction(value: f64) -> Option<String> {
+    if !value.is_finite() || value.fract() == 0.0 || value.abs() >= 1e15 {
+        return None;
+    }
+    let target = value.abs();
+    let tolerance = 1e-12 * target.max(1.0);
+    let (mut h0, mut h1) = (0u64, 1u64);
+    let (mut k0, mut k1) = (1u64, 0u64);
+    let mut x = target;
+    loop {
+        let a = x.floor() as u64;
+        let (h2, k2) = (a * h1 + h0, a * k1 + k0);
+        if k2 > MAX_DENOMINATOR {
+            return None;
+        }
+        if (h2 as f64 / k2 as f64 - target).abs() <= tolerance {
+            let sign = if value < 0.0 { "-" } else { "" };
+            return Some(format!("{}{}/{}", sign, h2, k2));
+        }
+        let rest = x - x.floor();
+        if rest == 0.0 {
+            return None;
+        }
+        x = 1.0 / rest;
+        (h0, h1, k0, k1) = (h1, h2, k1, k2);
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    pub fn decimal_is_unchanged() {
+        assert_eq!(Ok("255".to_string()), format_radix(255.0, Radix::Dec));
+        assert_eq!(Ok("2.5".to_string()), format_radix(2.5, Radix::Dec));
+    }
+
+    #[test]
+    pub fn integers_in_other_bases() {
+        assert_eq!(Ok("0xff".to_string()), format_radix(255.0, Radix::Hex));
+        assert_eq!(Ok("0o755".to_string()), format_radix(493.0, Radix::Oct));
+        assert_eq!(Ok("0b1010".to_string()), format_radix(10.0, Radix::Bin));
+        assert_eq!(Ok("-0b1010".to_string()), format_radix(-10.0, Radix::Bin));
+        assert_eq!(Ok("0x0".to_string()), format_radix(0.0, Radix::Hex));
+    }
+
+    #[test]
+    pub fn radix_names() {
+        assert_eq!(Some(Radix::Hex), Radix::from_name("hex"));
+        assert_eq!(Some(Radix::Dec), Radix::from_name("dec"));
+        assert_eq!(None, Radix::from_name("16"));
+    }
+
+    #[test]
+    pub fn non_integers_are_rejected() {
+        assert_eq!(
+            Err("cannot display non-integer in base 16"),
+            format_radix(2.5, Radix::Hex)
+        );
+        assert_eq!(
+            Err("cannot display non-integer in base 2"),
+            format_radix(f64::INFINITY, Radix::Bin)
+        );
+        assert_eq!(Err(TOO_LARGE), format_radix(1e300, Radix::Oct));
+    }
+
+    #[test]
+    pub fn bits_separate_the_fields() {
+        assert_eq!(
+            "0 01111111111 0000000000000000000000000000000000000000000000000000",
+            bits(1.0)
+        );
+        assert_eq!(
+            "1 10000000000 1000000000000000000000000000000000000000000000000000",
+            bits(-3.0)
+        );
+        assert_eq!(
+            "1 00000000000 0000000000000000000000000000000000000000000000000000",
+            bits(-0.0)
+        );
+        assert_eq!(
+            "0 00000000000 0000000000000000000000000000000000000000000000000001",
+            bits(f64::from_bits(1))
+        );
+    }
+
+    #[test]
+    pub fn bits_round_trip() {
+        for &x in &[
+            0.1,
+            -0.0,
+            1e-310,
+            f64::MIN_POSITIVE,
+            f64::MAX,
+            f64::INFINITY,
+        ] {
+            let fields: String = bits(x).split(' ').collect();
+            let n = u64::from_str_radix(&fields, 2).unwrap();
+            assert_eq!(x.to_bits(), n, "{}", x);
+        }
+    }
+
+    #[test]
+    pub fn hex_floats() {
+        assert_eq!("0x1.999999999999ap-4", hexfloat(0.1));
+        assert_eq!("0x1p+0", hexfloat(1.0));
+        assert_eq!("-0x1.8p-1", hexfloat(-0.75));
+        assert_eq!("0x0p+0", hexfloat(0.0));
+        assert_eq!("-0x0p+0", hexfloat(-0.0));
+        assert_eq!("0x0.0000000000001p-1022", hexfloat(f64::from_bits(1)));
+        assert_eq!("0x1p-1022", hexfloat(f64::MIN_POSITIVE));
+        assert_eq!("0x1.fffffffffffffp+1023", hexfloat(f64::MAX));
+        assert_eq!("-inf", hexfloat(f64::NEG_INFINITY));
+    }
+
+    #[test]
+    pub fn hex_floats_round_trip() {
+        let values = [
+            2.5,
+            -0.1,
+            1.0 / 3.0,
+            -0.0,
+            1e-310,
+            f64::from_bits(1),
+            f64::MIN_POSITIVE,
+            f64::MAX,
+            123456789.0,
+        ];
+        for &x in &values {
+            let text = hexfloat(x);
+            let read = crate::parse::eval(&text).unwrap();
+            assert_eq!(x.to_bits(), read.to_bits(), "{}", text);
+        }
+    }
+
+    #[test]
+    pub fn results_are_formatted_to_precision() {
+        assert_eq!("0.3333333333333333", format_result(1.0 / 3.0, None));
+        assert_eq!("0", format_result(1.0 / 3.0, Some(0)));
+        assert_eq!("0.3333", format_result(1.0 / 3.0, Some(4)));
+        assert_eq!("2.5", format_result(2.5, None));
+        assert_eq!("2.5000", format_result(2.5, Some(4)));
+        assert_eq!("3", format_result(2.6, Some(0)));
+        assert_eq!("-1.2346", format_result(-1.23456, Some(4)));
+    }
+
+    #[test]
+    pub fn trailing_zeros_are_trimmed() {
+        assert_eq!("2", trim_zeros("2.000"));
+        assert_eq!("2.5", trim_zeros("2.500"));
+        assert_eq!("-0.125", trim_zeros("-0.125"));
+        assert_eq!("100", trim_zeros("100"));
+        assert_eq!("1.5e20", trim_zeros("1.500e20"));
+        assert_eq!("4.7m", trim_zeros("4.700m"));
+    }
+
+    #[test]
+    pub fn whole_numbers_have_no_point() {
+        assert_eq!("4", format_result(4.0, None));
+        assert_eq!("2.5", format_result(2.5, None));
+        assert_eq!("-17", format_result(-17.0, None));
+        assert_eq!("0", format_result(0.0, None));
+        assert_eq!("9999999999999998", format_result(9999999999999998.0, None));
+    }
+
+    #[test]
+    pub fn large_and_small_magnitudes_are_scientific() {
+        assert_eq!("1e20", format_result(1e20, None));
+        assert_eq!("-2.5e16", format_result(-2.5e16, None));
+        assert_eq!("1.5e-7", format_result(1.5e-7, None));
+        assert_eq!("0.000001", format_result(1e-6, None));
+        assert_eq!("1.00e20", format_result(1e20, Some(2)));
+    }
+
+    #[test]
+    pub fn scientific_whatever_the_magnitude() {
+        assert_eq!("1.5e3", format_sci(1500.0, None));
+        assert_eq!("-2.5e-1", format_sci(-0.25, None));
+        assert_eq!("0e0", format_sci(0.0, None));
+        assert_eq!("1.50e3", format_sci(1500.0, Some(2)));
+        assert_eq!("1.5E3", upper_exponent(&format_sci(1500.0, None)));
+        assert_eq!("1.5E-7", upper_exponent(&format_result(1.5e-7, None)));
+        assert_eq!("1500", upper_exponent("1500"));
+    }
+
+    #[test]
+    pub fn si_prefixes_scale_results() {
+        assert_eq!("4.7m", format_si(0.0047, None));
+        assert_eq!("1.5k", format_si(1500.0, None));
+        assert_eq!("470k", format_si(470_000.0, None));
+        assert_eq!("-22µ", format_si(-2.2e-5, None));
+        assert_eq!("1f", format_si(1e-15, None));
+        assert_eq!("999T", format_si(9.99e14, None));
+        assert_eq!("12", format_si(12.0, None));
+        assert_eq!("0", format_si(0.0, None));
+        assert_eq!("3.14M", format_si(3_141_593.0, Some(2)));
+    }
+
+    #[test]
+    pub fn si_falls_back_to_scientific_outside_the_prefixes() {
+        assert_eq!("1e15", format_si(1e15, None));
+        assert_eq!("1.5e-16", format_si(1.5e-16, None));
+        assert_eq!("2.0e20", format_si(2e20, Some(1)));
+    }
+
+    #[test]
+    pub fn numbers_in_words() {
+        let words = |x| words(x).unwrap();
+        assert_eq!("forty-two", words(42.0));
+        assert_eq!("one hundred", words(100.0));
+        assert_eq!("one million", words(1_000_000.0));
+        assert_eq!("minus seventeen", words(-17.0));
+        assert_eq!("zero", words(0.0));
+        assert_eq!("three point five", words(3.5));
+        assert_eq!("minus zero point two five", words(-0.25));
+        assert_eq!("two thousand", words(2000.0));
+        assert_eq!(
+            "nine hundred ninety-nine trillion one billion twenty thousand three hundred five",
+            words(999_001_000_020_305.0)
+        );
+    }
+
+    #[test]
+    pub fn numbers_that_cannot_be_said() {
+        assert_eq!(Err(TOO_LARGE_FOR_WORDS), words(1e15));
+        assert_eq!(Err(TOO_LARGE_FOR_WORDS), words(f64::NAN));
+        assert_eq!(Err(TOO_MANY_DECIMALS), words(1.0 / 3.0));
+    }
+
+    #[test]
+    pub fn simple_fractions_are_found() {
+        assert_eq!(Some("3/4".to_string()), fraction(0.75));
+        assert_eq!(Some("1/3".to_string()), fraction(1.0 / 3.0));
+        assert_eq!(Some("-22/7".to_string()), fraction(-22.0 / 7.0));
+        assert_eq!(Some("3/10".to_string()), fraction(0.1 + 0.2));
+        assert_eq!(Some("1/10000".to_string()), fraction(0.0001));
+    }
+
+    #[test]
+    pub fn values_without_a_good_fraction_have_none() {
+        assert_eq!(None, fraction(std::f64::consts::PI));
+        assert_eq!(None, fraction(2.0f64.sqrt()));
+        assert_eq!(None, fraction(0.00001));
+        assert_eq!(None, fraction(3.0));
+        assert_eq!(None, fraction(f64::NAN));
+    }
+}
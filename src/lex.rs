@@ -1,69 +1,434 @@
-use std::iter::{Enumerate, Peekable};
-use std::str::Chars;
+use std::borrow::Cow;
+use std::fmt;
+use std::iter::Peekable;
+use std::ops::Range;
+use std::str::CharIndices;
 
-#[derive(Debug, PartialEq, Copy, Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     LParen,
     RParen,
+    LBracket,
+    RBracket,
+    LBrace,
+    RBrace,
+    Bar,
+    Amp,
     Plus,
     Dash,
     Caret,
     Slash,
+    DoubleSlash,
     Star,
     Percent,
+    Comma,
+    Semicolon,
+    Bang,
+    Equals,
+    EqEq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Shl,
+    Shr,
     Number(f64),
+    Ident(String),
 }
 
 pub const UNKNOWN_SYMBOL: &str = "unknown symbol";
+pub const PRIMES_DISABLED: &str = "primes in names are disabled";
+pub const INVALID_DIGIT: &str = "invalid digit for this base";
+pub const MISSING_DIGITS: &str = "expected digits after base prefix";
+pub const INTEGER_TOO_LARGE: &str = "integer literal is too large";
+pub const MISPLACED_SEPARATOR: &str = "digit separator must be between digits";
+pub const SECOND_DECIMAL_POINT: &str = "number has a second decimal point";
+pub const INVALID_NUMBER: &str = "not a valid number";
+pub const MISSING_BINARY_EXPONENT: &str = "expected a binary exponent like p3 after a hex float";
 
+// positions count chars from 0, not bytes or display columns, so `×` is one
+// position; where to draw a caret under one is worked out when it is printed
 pub type TokenPosition = usize;
-pub type LexErr = (TokenPosition, &'static str);
-pub type LexResult = Result<(TokenPosition, Token), LexErr>;
 
-fn read_num(iter: &mut Peekable<Enumerate<impl Iterator<Item = char>>>) -> LexResult {
-    let mut num = String::new();
+// the chars a token covers, from its first to one past its last
+pub type Span = Range<TokenPosition>;
+
+/// An input that can't be read, with the chars (counting from 0) of where it
+/// goes wrong.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct LexErr {
+    pub span: Span,
+    pub message: &'static str,
+}
+
+impl LexErr {
+    // an error at the single char at `pos`
+    pub fn new(pos: TokenPosition, message: &'static str) -> Self {
+        LexErr::spanning(pos..pos + 1, message)
+    }
+
+    pub fn spanning(span: Span, message: &'static str) -> Self {
+        LexErr { span, message }
+    }
+}
+
+impl fmt::Display for LexErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.span.start)
+    }
+}
+
+impl std::error::Error for LexErr {}
+
+pub type LexResult = Result<(Span, Token), LexErr>;
+// a token found by the functions below, which only know where it starts
+type Lexed = Result<(TokenPosition, Token), LexErr>;
+
+// replaces the chars in `range` of the old input with `new_text`
+#[derive(Debug, Clone)]
+pub struct Edit<'a> {
+    pub range: Range<TokenPosition>,
+    pub new_text: &'a str,
+}
+
+// the chars of the input, numbered by their position, which also knows their
+// byte offsets so that a token can be read from the input without copying it
+#[derive(Clone)]
+struct Cursor<'a> {
+    input: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+    // the position of the next char
+    pos: TokenPosition,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(input: &'a str) -> Self {
+        Cursor {
+            input,
+            chars: input.char_indices().peekable(),
+            pos: 0,
+        }
+    }
+
+    fn peek(&mut self) -> Option<(TokenPosition, char)> {
+        let pos = self.pos;
+        self.chars.peek().map(|&(_, c)| (pos, c))
+    }
+
+    // the byte offset of the next char
+    fn offset(&mut self) -> usize {
+        self.chars.peek().map_or(self.input.len(), |&(i, _)| i)
+    }
+}
+
+impl<'a> Iterator for Cursor<'a> {
+    type Item = (TokenPosition, char);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (_, c) = self.chars.next()?;
+        self.pos += 1;
+        Some((self.pos - 1, c))
+    }
+}
+
+// integers written as 0x.., 0o.. or 0b.., where any trailing letter or digit
+// that is not valid in the base is an error rather than the start of a new token,
+// as is a value too large for an i64
+fn read_radix_int(
+    iter: &mut Cursor,
+    start: TokenPosition,
+    prefix_pos: TokenPosition,
+    radix: u32,
+) -> Lexed {
+    let from = iter.offset();
+    while let Some((i, c)) = iter.peek() {
+        if !c.is_ascii_alphanumeric() {
+            break;
+        }
+        if !c.is_digit(radix) {
+            return Err(LexErr::new(i, INVALID_DIGIT));
+        }
+        iter.next();
+    }
+    let digits = &iter.input[from..iter.offset()];
+    if digits.is_empty() {
+        return Err(LexErr::new(prefix_pos, MISSING_DIGITS));
+    }
+    match i64::from_str_radix(digits, radix) {
+        Ok(n) => Ok((start, Token::Number(n as f64))),
+        Err(_) => Err(LexErr::spanning(start..iter.pos, INTEGER_TOO_LARGE)),
+    }
+}
+
+// whether the digits after a 0x prefix are followed by a point or a `p`, which
+// makes them the mantissa of a hex float rather than an integer
+fn is_hex_float(mut iter: Cursor) -> bool {
+    while let Some((_, c)) = iter.peek() {
+        if !c.is_ascii_hexdigit() {
+            return matches!(c, '.' | 'p' | 'P');
+        }
+        iter.next();
+    }
+    false
+}
+
+// `x` times 2 to the `exp`, in steps that stay in range so that only the last
+// one can round
+fn times_pow2(mut x: f64, mut exp: i64) -> f64 {
+    while exp.abs() > 1000 && x != 0.0 && x.is_finite() {
+        let step = exp.signum() * 1000;
+        x *= 2f64.powi(step as i32);
+        exp -= step;
+    }
+    x * 2f64.powi(exp as i32)
+}
+
+// hex floats like 0x1.8p3, a hex mantissa times a power of two written in
+// decimal, as `:hexfloat` shows results; the `p` can't be left out, since the
+// digits alone would read as an integer
+fn read_hex_float(iter: &mut Cursor, start: TokenPosition) -> Lexed {
+    let mut mantissa: u64 = 0;
+    let mut exponent: i64 = 0;
+    let mut found_dot = false;
+    while let Some((i, c)) = iter.peek() {
+        if c == '.' {
+            if found_dot {
+                return Err(LexErr::new(i, SECOND_DECIMAL_POINT));
+            }
+            found_dot = true;
+        } else if let Some(d) = c.to_digit(16) {
+            // digits past the 16 that fit are too small to change the value,
+            // but whole ones still scale it
+            if mantissa >> 60 == 0 {
+                mantissa = mantissa << 4 | d as u64;
+                if found_dot {
+                    exponent -= 4;
+                }
+            } else if !found_dot {
+                exponent += 4;
+            }
+        } else {
+            break;
+        }
+        iter.next();
+    }
+    match iter.peek() {
+        Some((_, 'p' | 'P')) => {
+            iter.next();
+        }
+        Some((i, c)) if c.is_ascii_alphanumeric() => return Err(LexErr::new(i, INVALID_DIGIT)),
+        _ => return Err(LexErr::spanning(start..iter.pos, MISSING_BINARY_EXPONENT)),
+    }
+    let negative = match iter.peek() {
+        Some((_, c @ ('+' | '-'))) => {
+            iter.next();
+            c == '-'
+        }
+        _ => false,
+    };
+    let mut power: Option<i64> = None;
+    while let Some((_, c)) = iter.peek() {
+        let d = match c.to_digit(10) {
+            Some(d) => d as i64,
+            None => break,
+        };
+        // anything this large is already zero or infinite
+        power = Some((power.unwrap_or(0) * 10 + d).min(100_000));
+        iter.next();
+    }
+    let power = match power {
+        Some(power) if negative => -power,
+        Some(power) => power,
+        None => return Err(LexErr::spanning(start..iter.pos, MISSING_BINARY_EXPONENT)),
+    };
+    Ok((
+        start,
+        Token::Number(times_pow2(mantissa as f64, exponent + power)),
+    ))
+}
+
+// an `e` after a number is an exponent only when digits follow it, after an
+// optional sign, so that `2e` and `2exp(1)` multiply the number by a name
+fn starts_exponent(rest: &str) -> bool {
+    let mut chars = rest.chars();
+    if !matches!(chars.next(), Some('e' | 'E')) {
+        return false;
+    }
+    let digit = match chars.next() {
+        Some('+' | '-') => chars.next(),
+        c => c,
+    };
+    matches!(digit, Some(c) if c.is_ascii_digit())
+}
+
+// how many chars after a token lexing it can look at, which is the most for a
+// number, where `2e+5` is one token but `2e+x` is four
+const LOOKAHEAD: usize = 3;
+
+// decimal numbers, where `_` may separate digits as in 1_000_000; errors point
+// at the char that can't be part of the number, or at the whole of a number
+// that doesn't read as one, like "."
+fn read_num(iter: &mut Cursor) -> Lexed {
     let mut found_dot = false;
+    let mut separated = false;
+    // the last char that was part of the number
+    let mut last = None;
 
-    let mut pos = 0;
+    let start = iter.pos;
+    let from = iter.offset();
+    if let Some((_, '0')) = iter.peek() {
+        iter.next();
+        let radix = match iter.peek() {
+            Some((_, 'x')) => Some(16),
+            Some((_, 'o')) => Some(8),
+            Some((_, 'b')) => Some(2),
+            _ => None,
+        };
+        if let (Some(radix), Some((prefix_pos, _))) = (radix, iter.peek()) {
+            iter.next();
+            if radix == 16 && is_hex_float(iter.clone()) {
+                return read_hex_float(iter, start);
+            }
+            return read_radix_int(iter, start, prefix_pos, radix);
+        }
+        last = Some('0');
+    }
     while let Some((i, c)) = iter.peek() {
-        pos = *i;
-        if *c == '.' {
+        if c == '_' {
+            iter.next();
+            let between_digits = matches!(last, Some(c) if c.is_ascii_digit())
+                && matches!(iter.peek(), Some((_, c)) if c.is_ascii_digit());
+            if !between_digits {
+                return Err(LexErr::new(i, MISPLACED_SEPARATOR));
+            }
+            separated = true;
+            continue;
+        }
+        if c == '.' {
             if found_dot {
-                break;
-            } else {
-                found_dot = true;
+                return Err(LexErr::new(i, SECOND_DECIMAL_POINT));
             }
+            found_dot = true;
+        }
+        if !(c.is_ascii_digit() || c == '.') {
+            break;
         }
-        if c.is_ascii_digit() || *c == '.' {
-            num.push(*c);
+        last = Some(c);
+        iter.next();
+    }
+    if last.is_none() {
+        return Err(LexErr::new(start, UNKNOWN_SYMBOL));
+    }
+    if starts_exponent(&iter.input[iter.offset()..]) {
+        iter.next();
+        if let Some((_, '+' | '-')) = iter.peek() {
             iter.next();
-        } else {
+        }
+        while let Some((_, c)) = iter.peek() {
+            if !c.is_ascii_digit() {
+                break;
+            }
+            iter.next();
+        }
+    }
+    // only numbers with separators need copying to drop them
+    let digits = &iter.input[from..iter.offset()];
+    let digits = if separated {
+        Cow::Owned(digits.replace('_', ""))
+    } else {
+        Cow::Borrowed(digits)
+    };
+    match digits.parse() {
+        Ok(n) => Ok((start, Token::Number(n))),
+        Err(_) => Err(LexErr::spanning(start..iter.pos, INVALID_NUMBER)),
+    }
+}
+
+// names start with a letter and continue with letters, digits or underscores,
+// optionally followed by primes (x', f'') when they are enabled; a lone `_` is
+// also a name
+fn read_ident(iter: &mut Cursor, pos: TokenPosition, primes: bool) -> Lexed {
+    let mut ident = String::new();
+    while let Some((_, c)) = iter.peek() {
+        if !(c.is_ascii_alphanumeric() || c == '_') {
             break;
         }
+        ident.push(c);
+        iter.next();
+    }
+    if ident.starts_with('_') && ident.len() > 1 {
+        return Err(LexErr::spanning(pos..pos + ident.len(), UNKNOWN_SYMBOL));
     }
-    match num.parse() {
-        Ok(n) => Ok((pos, Token::Number(n))),
-        Err(_) => Err((pos, UNKNOWN_SYMBOL)),
+    while let Some((i, '\'')) = iter.peek() {
+        if !primes {
+            return Err(LexErr::new(i, PRIMES_DISABLED));
+        }
+        ident.push('\'');
+        iter.next();
     }
+    Ok((pos, Token::Ident(ident)))
 }
 
-fn next_token(iter: &mut Peekable<Enumerate<impl Iterator<Item = char>>>) -> Option<LexResult> {
+fn next_token(iter: &mut Cursor, primes: bool) -> Option<Lexed> {
     use Token::*;
 
-    while let Some((_, c)) = iter.peek() {
+    while let Some((i, c)) = iter.peek() {
         if c.is_whitespace() {
             iter.next();
             continue;
+        } else if c == '#' {
+            // a comment runs to the end of the input
+            iter.for_each(drop);
+            return None;
         } else {
+            // tokens which become a different token when directly followed by
+            // another char, like `<=` and `<<`, or `**` which is an alias for `^`
+            let single = match c {
+                '<' => Some(Lt),
+                '>' => Some(Gt),
+                '!' => Some(Bang),
+                '=' => Some(Equals),
+                '*' => Some(Star),
+                '/' => Some(Slash),
+                _ => None,
+            };
+            if let Some(single) = single {
+                iter.next();
+                let double = match (c, iter.peek().map(|(_, next)| next)) {
+                    ('<', Some('=')) => Le,
+                    ('<', Some('<')) => Shl,
+                    ('>', Some('=')) => Ge,
+                    ('>', Some('>')) => Shr,
+                    ('!', Some('=')) => Ne,
+                    ('=', Some('=')) => EqEq,
+                    ('*', Some('*')) => Caret,
+                    ('/', Some('/')) => DoubleSlash,
+                    _ => return Some(Ok((i, single))),
+                };
+                iter.next();
+                return Some(Ok((i, double)));
+            }
             let token = match c {
                 '(' => LParen,
                 ')' => RParen,
+                '[' => LBracket,
+                ']' => RBracket,
+                '{' => LBrace,
+                '}' => RBrace,
+                '|' => Bar,
+                '&' => Amp,
                 '+' => Plus,
-                '-' => Dash,
-                '*' => Star,
-                '/' => Slash,
+                '-' | '−' => Dash,
+                '×' | '·' => Star,
+                '÷' => Slash,
                 '%' => Percent,
                 '^' => Caret,
+                ',' => Comma,
+                ';' => Semicolon,
+                c if c.is_ascii_alphabetic() || c == '_' => {
+                    return Some(read_ident(iter, i, primes))
+                }
                 _ => return Some(read_num(iter)),
             };
             let (i, _) = iter.next()?;
@@ -73,21 +438,607 @@ fn next_token(iter: &mut Peekable<Enumerate<impl Iterator<Item = char>>>) -> Opt
     None
 }
 
+pub fn tokenize(input: &str) -> Result<Vec<(Span, Token)>, LexErr> {
+    Lexer::new(input).collect()
+}
+
+#[derive(Clone)]
 pub struct Lexer<'a> {
-    chars: Peekable<Enumerate<Chars<'a>>>,
+    chars: Cursor<'a>,
+    primes: bool,
 }
 
 impl<'a> Lexer<'a> {
     pub fn new(input: &'a str) -> Self {
         Lexer {
-            chars: input.chars().enumerate().peekable(),
+            chars: Cursor::new(input),
+            primes: false,
         }
     }
+
+    pub fn allow_primes(mut self, primes: bool) -> Self {
+        self.primes = primes;
+        self
+    }
+
+    /// Lexes this lexer's input, which is the input `old` was lexed from with
+    /// `edit` applied, reusing the tokens of `old` that the edit cannot have
+    /// changed. The result is the same as `tokenize` on the new input.
+    ///
+    /// Tokens ending far enough before the edit that it can't change them are
+    /// kept; lexing restarts after the last of them and stops once it reaches
+    /// the start of an old token past the edit, since the rest of the input
+    /// is then unchanged.
+    pub fn relex(
+        mut self,
+        old: &[(Span, Token)],
+        edit: &Edit,
+    ) -> Result<Vec<(Span, Token)>, LexErr> {
+        let kept = old.partition_point(|(span, _)| span.end + LOOKAHEAD <= edit.range.start);
+        let mut tokens = old[..kept].to_vec();
+        let resume = tokens.last().map_or(0, |(span, _)| span.end);
+        if resume > 0 {
+            self.chars.nth(resume - 1);
+        }
+
+        let edit_end = edit.range.start + edit.new_text.chars().count();
+        let shift = |pos: TokenPosition| pos + edit_end - edit.range.end;
+        let mut rest = old[kept..]
+            .iter()
+            .skip_while(|(span, _)| span.start < edit.range.end)
+            .peekable();
+        for token in self {
+            let (span, token) = token?;
+            while let Some((old_span, _)) = rest.peek() {
+                if shift(old_span.start) >= span.start {
+                    break;
+                }
+                rest.next();
+            }
+            if span.start >= edit_end {
+                if let Some((old_span, _)) = rest.peek() {
+                    if shift(old_span.start) == span.start {
+                        tokens.extend(rest.map(|(span, token)| {
+                            (shift(span.start)..shift(span.end), token.clone())
+                        }));
+                        return Ok(tokens);
+                    }
+                }
+            }
+            tokens.push((span, token));
+        }
+        Ok(tokens)
+    }
 }
 
 impl<'a> Iterator for Lexer<'a> {
     type Item = LexResult;
+
+    // a token ends where the next unread char starts, as the chars looked at
+    // past its end aren't read
     fn next(&mut self) -> Option<Self::Item> {
-        return next_token(&mut self.chars);
+        let token = next_token(&mut self.chars, self.primes)?;
+        Some(token.map(|(start, token)| (start..self.chars.pos, token)))
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::rng::Rng;
+    use Token::*;
+
+    fn lex(input: &str) -> Vec<LexResult> {
+        Lexer::new(input).collect()
+    }
+
+    #[test]
+    pub fn single_char_operators_are_lexed() {
+        let cases = [
+            ('(', LParen),
+            (')', RParen),
+            ('[', LBracket),
+            (']', RBracket),
+            ('{', LBrace),
+            ('}', RBrace),
+            ('|', Bar),
+            ('+', Plus),
+            ('-', Dash),
+            ('*', Star),
+            ('/', Slash),
+            ('%', Percent),
+            ('^', Caret),
+            (',', Comma),
+            (';', Semicolon),
+            ('!', Bang),
+            ('=', Equals),
+            ('<', Lt),
+            ('>', Gt),
+            ('&', Amp),
+        ];
+        for (c, token) in cases.iter() {
+            assert_eq!(vec![Ok((0..1, token.clone()))], lex(&c.to_string()));
+        }
+    }
+
+    #[test]
+    pub fn comparison_operators_are_lexed() {
+        let cases = [("==", EqEq), ("!=", Ne), ("<=", Le), (">=", Ge)];
+        for (s, token) in cases.iter() {
+            assert_eq!(vec![Ok((0..2, token.clone()))], lex(s));
+        }
+        assert_eq!(
+            Ok(vec![(0..1, Number(3.0)), (1..3, Ne), (3..4, Number(6.0))]),
+            tokenize("3!=6")
+        );
+        assert_eq!(Ok(vec![(0..1, Bang), (2..3, Equals)]), tokenize("! ="));
+        assert_eq!(Ok(vec![(0..2, EqEq), (2..3, Equals)]), tokenize("==="));
+        assert_eq!(Ok(vec![(0..1, Lt), (1..2, Gt)]), tokenize("<>"));
+    }
+
+    #[test]
+    pub fn shifts_are_lexed() {
+        assert_eq!(vec![Ok((0..2, Shl))], lex("<<"));
+        assert_eq!(vec![Ok((0..2, Shr))], lex(">>"));
+        assert_eq!(Ok(vec![(0..2, Shl), (2..3, Lt)]), tokenize("<<<"));
+        assert_eq!(Ok(vec![(0..2, Shr), (2..3, Equals)]), tokenize(">>="));
+    }
+
+    #[test]
+    pub fn double_star_is_caret() {
+        assert_eq!(
+            Ok(vec![
+                (0..1, Number(2.0)),
+                (1..3, Caret),
+                (3..4, Number(3.0))
+            ]),
+            tokenize("2**3")
+        );
+        assert_eq!(Ok(vec![(0..2, Caret), (2..3, Star)]), tokenize("***"));
+        assert_eq!(Ok(vec![(0..1, Star), (2..3, Star)]), tokenize("* *"));
+    }
+
+    #[test]
+    pub fn double_slash_is_one_token() {
+        assert_eq!(
+            Ok(vec![
+                (0..1, Number(7.0)),
+                (2..4, DoubleSlash),
+                (5..6, Number(2.0))
+            ]),
+            tokenize("7 // 2")
+        );
+        assert_eq!(Ok(vec![(0..1, Slash), (2..3, Slash)]), tokenize("/ /"));
+        assert_eq!(
+            Ok(vec![(0..2, DoubleSlash), (2..3, Slash)]),
+            tokenize("///")
+        );
+    }
+
+    #[test]
+    pub fn unicode_operators_are_lexed() {
+        let cases = [('×', Star), ('·', Star), ('÷', Slash), ('−', Dash)];
+        for (c, token) in cases.iter() {
+            assert_eq!(vec![Ok((0..1, token.clone()))], lex(&c.to_string()));
+        }
+        assert_eq!(
+            Ok(vec![
+                (0..1, Number(3.0)),
+                (2..3, Star),
+                (4..5, Number(4.0)),
+                (6..7, Slash)
+            ]),
+            tokenize("3 × 4 ÷")
+        );
+    }
+
+    #[test]
+    pub fn operator_positions_skip_whitespace() {
+        assert_eq!(
+            vec![Ok((1..2, Percent)), Ok((2..3, Percent)), Ok((4..5, Caret))],
+            lex(" %% ^")
+        );
+    }
+
+    #[test]
+    pub fn percent_is_lexed_between_operands() {
+        assert_eq!(Some(Ok((2..3, Percent))), Lexer::new("8 % 3").nth(1));
+    }
+
+    #[test]
+    pub fn ident_is_lexed() {
+        assert_eq!(
+            vec![Ok((1..5, Ident("sqrt".to_string()))), Ok((5..6, LParen))],
+            lex(" sqrt(")
+        );
+    }
+
+    #[test]
+    pub fn ident_may_contain_digits_and_underscores() {
+        assert_eq!(vec![Ok((0..3, Ident("v_0".to_string())))], lex("v_0"));
+        assert_eq!(vec![Ok((0..3, Ident("x2y".to_string())))], lex("x2y"));
+        assert_eq!(
+            Some(Err(LexErr::spanning(0..2, UNKNOWN_SYMBOL))),
+            Lexer::new("_v").next()
+        );
+        assert_eq!(vec![Ok((1..2, Ident("_".to_string())))], lex(" _"));
+    }
+
+    #[test]
+    pub fn primes_are_opt_in() {
+        assert_eq!(
+            Some(Err(LexErr::new(1, PRIMES_DISABLED))),
+            Lexer::new("x'").next()
+        );
+        let lex_primes = |input| Lexer::new(input).allow_primes(true).collect::<Vec<_>>();
+        assert_eq!(vec![Ok((0..2, Ident("x'".to_string())))], lex_primes("x'"));
+        assert_eq!(
+            vec![Ok((0..5, Ident("f_1''".to_string()))), Ok((5..6, LParen))],
+            lex_primes("f_1''(")
+        );
+    }
+
+    fn first_token(input: &str) -> Option<Result<Token, LexErr>> {
+        Lexer::new(input).next().map(|r| r.map(|(_, token)| token))
+    }
+
+    #[test]
+    pub fn scientific_notation() {
+        assert_eq!(Some(Ok(Number(1000.0))), first_token("1e3"));
+        assert_eq!(Some(Ok(Number(0.015))), first_token("1.5E-2"));
+        assert_eq!(Some(Ok(Number(6.022e23))), first_token("6.022e23"));
+        assert_eq!(Some(Ok(Number(250.0))), first_token("2.5e+2"));
+        assert_eq!(Some(Ok(Number(0.001))), first_token("1E-3"));
+    }
+
+    #[test]
+    pub fn e_without_exponent_digits_starts_a_name() {
+        let name = |span, name: &str| (span, Ident(name.to_string()));
+        assert_eq!(
+            Ok(vec![(0..1, Number(2.0)), name(1..2, "e")]),
+            tokenize("2e")
+        );
+        assert_eq!(
+            Ok(vec![(0..1, Number(2.0)), name(1..4, "exp"), (4..5, LParen)]),
+            tokenize("2exp(")
+        );
+        assert_eq!(
+            Ok(vec![(0..1, Number(1.0)), name(1..2, "e"), (2..3, Plus)]),
+            tokenize("1e+")
+        );
+        assert_eq!(
+            Ok(vec![(0..5, Number(25.0)), name(5..6, "x")]),
+            tokenize("2.5e1x")
+        );
+        assert_eq!(
+            Ok(vec![
+                (0..3, Number(2.5)),
+                name(3..4, "e"),
+                (4..5, Dash),
+                name(5..6, "x")
+            ]),
+            tokenize("2.5e-x")
+        );
+    }
+
+    #[test]
+    pub fn radix_prefixed_integers() {
+        assert_eq!(vec![Ok((0..4, Number(255.0)))], lex("0xff"));
+        assert_eq!(vec![Ok((0..4, Number(255.0)))], lex("0xFF"));
+        assert_eq!(vec![Ok((1..6, Number(493.0)))], lex(" 0o755"));
+        assert_eq!(vec![Ok((0..6, Number(10.0)))], lex("0b1010"));
+        assert_eq!(
+            vec![Ok((0..4, Number(255.0))), Ok((5..6, Plus))],
+            lex("0xff +")
+        );
+    }
+
+    #[test]
+    pub fn invalid_radix_digit_is_rejected() {
+        assert_eq!(
+            Some(Err(LexErr::new(4, INVALID_DIGIT))),
+            Lexer::new("0b102").next()
+        );
+        assert_eq!(
+            Some(Err(LexErr::new(3, INVALID_DIGIT))),
+            Lexer::new("0o78").next()
+        );
+        assert_eq!(
+            Some(Err(LexErr::new(3, INVALID_DIGIT))),
+            Lexer::new("0xfg").next()
+        );
+        assert_eq!(
+            Some(Err(LexErr::new(1, MISSING_DIGITS))),
+            Lexer::new("0x").next()
+        );
+        assert_eq!(
+            Some(Err(LexErr::new(1, MISSING_DIGITS))),
+            Lexer::new("0b + 1").next()
+        );
+        assert_eq!(
+            Some(Err(LexErr::new(20, INVALID_DIGIT))),
+            Lexer::new("0xffffffffffffffffffg").next()
+        );
+    }
+
+    #[test]
+    pub fn radix_integers_must_fit_in_64_bits() {
+        assert_eq!(
+            vec![Ok((0..18, Number(i64::MAX as f64)))],
+            lex("0x7fffffffffffffff")
+        );
+        assert_eq!(
+            vec![
+                Ok((0..1, Number(1.0))),
+                Ok((2..3, Plus)),
+                Err(LexErr::spanning(4..22, INTEGER_TOO_LARGE))
+            ],
+            lex("1 + 0x8000000000000000")
+        );
+        assert_eq!(
+            Some(Err(LexErr::spanning(0..67, INTEGER_TOO_LARGE))),
+            Lexer::new(&format!("0b1{}", "0".repeat(64))).next()
+        );
+    }
+
+    #[test]
+    pub fn hex_floats() {
+        assert_eq!(vec![Ok((0..7, Number(12.0)))], lex("0x1.8p3"));
+        assert_eq!(vec![Ok((0..6, Number(0.375)))], lex("0x3p-3"));
+        assert_eq!(Some(Ok(Number(0.1))), first_token("0x1.999999999999ap-4"));
+        assert_eq!(Some(Ok(Number(0.5))), first_token("0x.8P0"));
+        assert_eq!(
+            Some(Ok(Number(f64::from_bits(1)))),
+            first_token("0x0.0000000000001p-1022")
+        );
+        assert_eq!(
+            Some(Ok(Number(f64::from_bits(1)))),
+            first_token("0x1p-1074")
+        );
+        assert_eq!(Some(Ok(Number(f64::INFINITY))), first_token("0x1p1024"));
+        assert_eq!(Some(Ok(Number(0.0))), first_token("0x1p-99999999"));
+        assert_eq!(
+            vec![
+                Ok((0..5, Number(8.0))),
+                Ok((5..6, Plus)),
+                Ok((6..7, Number(1.0)))
+            ],
+            lex("0x1p3+1")
+        );
+    }
+
+    #[test]
+    pub fn hex_floats_need_an_exponent() {
+        assert_eq!(
+            Some(Err(LexErr::spanning(0..5, MISSING_BINARY_EXPONENT))),
+            Lexer::new("0x1.8 + 1").next()
+        );
+        assert_eq!(
+            Some(Err(LexErr::spanning(0..5, MISSING_BINARY_EXPONENT))),
+            Lexer::new("0x1p-").next()
+        );
+        assert_eq!(
+            Some(Err(LexErr::new(5, INVALID_DIGIT))),
+            Lexer::new("0x1.8g").next()
+        );
+        assert_eq!(
+            Some(Err(LexErr::new(5, SECOND_DECIMAL_POINT))),
+            Lexer::new("0x1.8.1p0").next()
+        );
+    }
+
+    #[test]
+    pub fn underscores_separate_digits() {
+        assert_eq!(Some(Ok(Number(1_000_000.0))), first_token("1_000_000"));
+        assert_eq!(Some(Ok(Number(1000.5))), first_token("1_000.5"));
+        assert_eq!(Some(Ok(Number(0.25))), first_token("0.2_5"));
+        assert_eq!(vec![Ok((0..7, Number(12345.0)))], lex("12_34_5"));
+    }
+
+    #[test]
+    pub fn misplaced_separators_are_rejected() {
+        assert_eq!(
+            Some(Err(LexErr::new(1, MISPLACED_SEPARATOR))),
+            Lexer::new("5_").next()
+        );
+        assert_eq!(
+            Some(Err(LexErr::new(1, MISPLACED_SEPARATOR))),
+            Lexer::new("1__0").next()
+        );
+        assert_eq!(
+            Some(Err(LexErr::new(1, MISPLACED_SEPARATOR))),
+            Lexer::new("1_.5").next()
+        );
+        assert_eq!(
+            Some(Err(LexErr::new(2, MISPLACED_SEPARATOR))),
+            Lexer::new("1._5").next()
+        );
+        assert_eq!(
+            Some(Err(LexErr::spanning(0..2, UNKNOWN_SYMBOL))),
+            Lexer::new("_5").next()
+        );
+    }
+
+    #[test]
+    pub fn bad_numbers_point_at_the_right_char() {
+        assert_eq!(Err(LexErr::new(0, INVALID_NUMBER)), tokenize("."));
+        assert_eq!(Err(LexErr::new(4, INVALID_NUMBER)), tokenize("2 * ."));
+        assert_eq!(Err(LexErr::new(1, SECOND_DECIMAL_POINT)), tokenize(".."));
+        assert_eq!(Err(LexErr::new(2, SECOND_DECIMAL_POINT)), tokenize("1..2"));
+        assert_eq!(Err(LexErr::new(3, SECOND_DECIMAL_POINT)), tokenize("1.2.3"));
+        assert_eq!(
+            Err(LexErr::spanning(0..3, INVALID_NUMBER)),
+            tokenize(".e5+1")
+        );
+        assert_eq!(Err(LexErr::new(2, UNKNOWN_SYMBOL)), tokenize("1 $ 2"));
+    }
+
+    #[test]
+    pub fn number_may_end_the_input() {
+        assert_eq!(
+            Ok(vec![(0..1, Number(1.0)), (2..3, Plus), (4..6, Number(2.0))]),
+            tokenize("1 + 2.")
+        );
+        assert_eq!(Ok(vec![(1..5, Number(1.25))]), tokenize(" 1.25"));
+    }
+
+    #[test]
+    pub fn leading_zero_is_still_decimal() {
+        assert_eq!(Some(Ok(Number(0.0))), first_token("0"));
+        assert_eq!(Some(Ok(Number(7.0))), first_token("007"));
+        assert_eq!(Some(Ok(Number(0.5))), first_token("0.5"));
+        assert_eq!(Some(Ok(Number(0.0))), first_token("0e5"));
+    }
+
+    #[test]
+    pub fn tokenize_collects_all_tokens() {
+        assert_eq!(
+            Ok(vec![(0..1, Number(1.0)), (2..3, Plus), (4..5, Number(2.0))]),
+            tokenize("1 + 2")
+        );
+        assert_eq!(Ok(vec![]), tokenize("  "));
+    }
+
+    #[test]
+    pub fn comments_run_to_the_end() {
+        assert_eq!(tokenize("2+2"), tokenize("2+2 # note"));
+        assert_eq!(tokenize("2+2"), tokenize("2+2#3 $ 4"));
+        assert_eq!(Ok(vec![]), tokenize("# just a note"));
+    }
+
+    #[test]
+    pub fn tokenize_stops_at_first_error() {
+        assert_eq!(Err(LexErr::new(2, UNKNOWN_SYMBOL)), tokenize("1 $ 2"));
+    }
+
+    #[test]
+    pub fn spans_cover_whole_tokens() {
+        assert_eq!(
+            Ok(vec![
+                (0..3, Number(1.5)),
+                (4..6, Caret),
+                (7..11, Ident("sqrt".to_string())),
+                (11..12, LParen),
+                (12..16, Number(255.0)),
+            ]),
+            tokenize("1.5 ** sqrt(0xff")
+        );
+        assert_eq!(Ok(vec![(1..3, Le)]), tokenize(" <= "));
+    }
+
+    fn apply(input: &str, edit: &Edit) -> String {
+        let chars: Vec<char> = input.chars().collect();
+        let mut out: String = chars[..edit.range.start].iter().collect();
+        out.push_str(edit.new_text);
+        out.extend(&chars[edit.range.end..]);
+        out
+    }
+
+    fn relex(old_input: &str, edit: &Edit) -> Result<Vec<(Span, Token)>, LexErr> {
+        let old = tokenize(old_input).unwrap();
+        Lexer::new(&apply(old_input, edit)).relex(&old, edit)
+    }
+
+    #[test]
+    pub fn relex_joins_and_splits_tokens() {
+        let edit = |range, new_text| Edit { range, new_text };
+        assert_eq!(tokenize("12 + 3"), relex("1 + 3", &edit(1..1, "2")));
+        assert_eq!(tokenize("1 <= 3"), relex("1 < 3", &edit(3..3, "=")));
+        assert_eq!(tokenize("1 * 3"), relex("1 ** 3", &edit(3..4, "")));
+        assert_eq!(tokenize("1e5 + x"), relex("1 + x", &edit(1..1, "e5")));
+        assert_eq!(tokenize("1 + −x"), relex("1 + x", &edit(4..4, "−")));
+        assert_eq!(
+            Err(LexErr::new(2, UNKNOWN_SYMBOL)),
+            relex("1 + 2", &edit(2..3, "$"))
+        );
+    }
+
+    impl Rng {
+        fn text(&mut self, max_len: usize) -> String {
+            let alphabet: Vec<char> = "0123456789.eExob_axp#[]+-−*/=<>!^%(), ÷".chars().collect();
+            (0..self.below(max_len + 1))
+                .map(|_| alphabet[self.below(alphabet.len())])
+                .collect()
+        }
+    }
+
+    #[test]
+    pub fn relex_matches_lexing_from_scratch() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        let mut checked = 0;
+        while checked < 5000 {
+            let old_input = rng.text(20);
+            let old = match tokenize(&old_input) {
+                Ok(old) => old,
+                Err(_) => continue,
+            };
+            let len = old_input.chars().count();
+            let start = rng.below(len + 1);
+            let new_text = rng.text(4);
+            let edit = Edit {
+                range: start..start + rng.below(len - start + 1),
+                new_text: &new_text,
+            };
+            let new_input = apply(&old_input, &edit);
+            assert_eq!(
+                tokenize(&new_input),
+                Lexer::new(&new_input).relex(&old, &edit),
+                "'{}' edited with {:?}",
+                old_input,
+                edit
+            );
+            checked += 1;
+        }
+    }
+
+    #[test]
+    pub fn errors_display_their_position() {
+        assert_eq!(
+            "unknown symbol at position 2",
+            LexErr::new(2, UNKNOWN_SYMBOL).to_string()
+        );
+    }
+
+    #[test]
+    pub fn unknown_symbol_is_rejected() {
+        assert_eq!(
+            Some(Err(LexErr::new(2, UNKNOWN_SYMBOL))),
+            Lexer::new("  $").next()
+        );
+    }
+    #[test]
+    pub fn positions_count_chars_after_wide_ones() {
+        assert_eq!(
+            Ok(vec![
+                (0..1, Number(2.0)),
+                (1..2, Star),
+                (2..7, Number(1000.0)),
+                (8..9, Slash),
+                (10..13, Number(0.5)),
+            ]),
+            tokenize("2×1_000 ÷ 0.5")
+        );
+        assert_eq!(Err(LexErr::spanning(2..3, INVALID_NUMBER)), tokenize("2×."));
+    }
+
+    #[test]
+    pub fn long_inputs_are_lexed() {
+        let terms = ["1", "2.5", "1_000", "3e2", "0x1f", "x"];
+        let input = (0..5000)
+            .map(|i| terms[i % terms.len()])
+            .collect::<Vec<_>>()
+            .join(" + ");
+        let tokens = tokenize(&input).unwrap();
+        assert_eq!(9999, tokens.len());
+        let sum: f64 = tokens
+            .iter()
+            .filter_map(|(_, token)| match token {
+                Number(n) => Some(n),
+                _ => None,
+            })
+            .sum();
+        assert_eq!(833.0 * (1.0 + 2.5 + 1000.0 + 300.0 + 31.0) + 1.0 + 2.5, sum);
+        let len = input.chars().count();
+        assert_eq!(Some(&(len - 3..len, Number(2.5))), tokens.last());
     }
 }
@@ -1,27 +1,263 @@
 use colored::Colorize;
 use rustyline::error::ReadlineError::{Eof, Interrupted};
-use rustyline::Editor;
+use rustyline::{Editor, EventHandler};
+use std::fs::File;
+use std::hint::black_box;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use unicode_width::UnicodeWidthStr;
 
-mod lex;
-mod parse;
+mod field;
+mod format;
+pub mod lex;
+mod pair;
+pub mod parse;
+mod region;
+#[cfg(test)]
+mod rng;
+mod selftest;
 
-pub fn run() {
+pub use format::format_result;
+pub use region::{defined_region, Region};
+pub use selftest::{self_test, SelfTestReport};
+
+/// Settings given as `--name value` before any expression on the command line,
+/// which are applied like `:set name value` before anything is evaluated, and
+/// the fields to rewrite when rcalc is used as a filter.
+#[derive(Default)]
+pub struct Flags {
+    settings: Vec<(String, String)>,
+    fields: field::Fields,
+}
+
+const FLAGS: [&str; 6] = [
+    "max-depth",
+    "precision",
+    "base",
+    "field",
+    "delimiter",
+    "on-error",
+];
+
+fn apply_flag(session: &mut Session, name: &str, value: &str) -> Result<(), &'static str> {
+    match name {
+        "precision" => precision(session, value),
+        "base" => {
+            session.radix =
+                format::Radix::from_name(value).ok_or("usage: --base {dec|hex|oct|bin}")?;
+            Ok(())
+        }
+        _ => set(session, &format!("{} {}", name, value)),
+    }
+}
+
+/// Splits the leading flags from the rest of the arguments. Anything else that
+/// starts with `--` is the start of the expression, as in `rcalc --5`, and `-e`
+/// ends the flags explicitly.
+pub fn parse_flags(args: &[String]) -> Result<(Flags, &[String]), String> {
+    let mut flags = Flags::default();
+    let mut rest = args;
+    while let Some(arg) = rest.first() {
+        if arg == "-e" {
+            rest = &rest[1..];
+            break;
+        }
+        let name = match arg.strip_prefix("--") {
+            Some(name) if FLAGS.contains(&name) => name,
+            _ => break,
+        };
+        let value = rest
+            .get(1)
+            .ok_or_else(|| format!("missing value for '--{}'", name))?;
+        let invalid = |msg| format!("--{}: {}", name, msg);
+        match name {
+            "field" | "delimiter" | "on-error" => flags.fields.set(name, value).map_err(invalid)?,
+            _ => {
+                apply_flag(&mut Session::default(), name, value).map_err(invalid)?;
+                flags.settings.push((name.to_string(), value.to_string()));
+            }
+        }
+        rest = &rest[2..];
+    }
+    if flags.fields.column.is_none() && flags.fields != field::Fields::default() {
+        return Err("--delimiter and --on-error need --field".to_string());
+    }
+    if flags.fields.column.is_some() && rest.is_empty() {
+        return Err("--field needs an expression in terms of x".to_string());
+    }
+    Ok((flags, rest))
+}
+
+impl Session {
+    fn with_flags(flags: &Flags) -> Self {
+        let mut session = Session::default();
+        for (name, value) in &flags.settings {
+            apply_flag(&mut session, name, value).expect("flags are checked when parsed");
+        }
+        session
+    }
+}
+
+// parsing takes stack for each level of nesting, so input is read on a thread
+// with a stack known to fit the deepest that `max-depth` allows
+fn with_parser_stack<T: Send>(f: impl FnOnce() -> T + Send) -> T {
+    std::thread::scope(|scope| {
+        let thread = std::thread::Builder::new()
+            .stack_size(parse::STACK_SIZE)
+            .spawn_scoped(scope, f)
+            .expect("the parser thread can be started");
+        match thread.join() {
+            Ok(x) => x,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    })
+}
+
+pub fn run(flags: &Flags) {
+    with_parser_stack(|| run_session(flags))
+}
+
+fn run_session(flags: &Flags) {
     let prompt = ">>> ".yellow().to_string();
     let overflow = "... ".yellow().to_string();
 
     let mut rl = Editor::<()>::new();
+    let pairing = Arc::new(AtomicBool::new(true));
+    for key in pair::PairHandler::KEYS.iter() {
+        let handler = pair::PairHandler(Arc::clone(&pairing));
+        rl.bind_sequence(*key, EventHandler::Conditional(Box::new(handler)));
+    }
+    let history = history_path();
+    if let Some(path) = &history {
+        // there is no history to load on the first run
+        let _ = rl.load_history(path);
+    }
+    let mut session = Session::with_flags(flags);
+    let saved_vars = vars_path();
+    if let Some(path) = saved_vars.as_ref().filter(|path| path.exists()) {
+        // the variables were saved because autosave was on, so it still is
+        match load_vars(&mut session, path) {
+            Ok(()) => session.autosave = true,
+            Err(e) => eprintln!("could not load variables from '{}': {}", path.display(), e),
+        }
+    }
     loop {
-        if let State::Stop = process_line(&mut rl, &prompt, &overflow) {
+        pairing.store(session.autopair == AutoPair::On, Ordering::Relaxed);
+        if let State::Stop = process_line(&mut rl, &mut session, &prompt, &overflow) {
             break;
         }
     }
+    if let Some(path) = &history {
+        if let Err(e) = rl.save_history(path) {
+            eprintln!("could not save history to '{}': {}", path.display(), e);
+        }
+    }
+    if let Some(path) = &saved_vars {
+        let saved = match session.autosave {
+            true => save_vars(&session, path),
+            // so that they aren't loaded again
+            false if path.exists() => std::fs::remove_file(path),
+            false => Ok(()),
+        };
+        if let Err(e) = saved {
+            eprintln!("could not save variables to '{}': {}", path.display(), e);
+        }
+    }
 }
 
-pub fn compute(input: &str) {
-    match parse::eval(input) {
-        Ok(val) => println!("{}", val),
-        Err(parse::CalcErr::Lex(e)) => print_error_message(input, e),
-        Err(parse::CalcErr::Incomplete) => eprint!("Incomplete expression: '{}'", input),
+// RCALC_HISTORY overrides the default of ~/.rcalc_history
+fn history_path() -> Option<PathBuf> {
+    match std::env::var_os("RCALC_HISTORY") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".rcalc_history")),
+    }
+}
+
+// RCALC_VARS overrides the default of ~/.rcalc_vars
+fn vars_path() -> Option<PathBuf> {
+    match std::env::var_os("RCALC_VARS") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".rcalc_vars")),
+    }
+}
+
+// one `name = value` line for each variable, which `load_vars` reads back
+fn save_vars(session: &Session, path: &Path) -> io::Result<()> {
+    let mut out = File::create(path)?;
+    for (name, value) in vars(session) {
+        writeln!(out, "{} = {}", name, value)?;
+    }
+    Ok(())
+}
+
+// runs each line saved by `save_vars` as an assignment, skipping any that
+// isn't one with a note saying so
+fn load_vars(session: &mut Session, path: &Path) -> io::Result<()> {
+    for line in std::fs::read_to_string(path)?.lines() {
+        let skipped = match parse::parse_statement(line, &session.options, &session.env) {
+            Ok(statement @ parse::Statement::Assign(..)) => statement
+                .exec(&mut session.env)
+                .err()
+                .map(|e| e.to_string()),
+            Ok(parse::Statement::Expr(_)) => Some("not an assignment".to_string()),
+            Err(e) => Some(e.to_string()),
+        };
+        if let Some(why) = skipped {
+            eprintln!("skipping '{}' from '{}': {}", line, path.display(), why);
+        }
+    }
+    Ok(())
+}
+
+pub fn compute(input: &str, flags: &Flags) -> bool {
+    with_parser_stack(|| compute_input(input, flags))
+}
+
+fn compute_input(input: &str, flags: &Flags) -> bool {
+    if flags.fields.column.is_some() {
+        return filter(input, flags);
+    }
+    match run_statements(&mut Session::with_flags(flags), input, &mut 0) {
+        Ok(()) => true,
+        Err(e) => {
+            print_calc_error(input, e);
+            false
+        }
+    }
+}
+
+// evaluates `input` with `x` bound to a field of each record on stdin
+fn filter(input: &str, flags: &Flags) -> bool {
+    let mut session = Session::with_flags(flags);
+    session.env.set("x", 0.0);
+    let expr = match parse::parse_with(input, &session.options, &session.env) {
+        Ok(expr) => expr,
+        Err(e) => {
+            print_calc_error(input, e);
+            return false;
+        }
+    };
+    let eval = |x| {
+        session.env.set("x", x);
+        match expr.eval(&session.env) {
+            Ok(val) => Ok(format_decimal(&session, val)),
+            Err(parse::CalcErr::Math { msg, .. }) => Err(msg),
+            Err(_) => unreachable!("evaluating only fails with a math error"),
+        }
+    };
+    let stdin = io::stdin();
+    match flags
+        .fields
+        .filter(stdin.lock(), io::stdout(), io::stderr(), eval)
+    {
+        Ok(ok) => ok,
+        Err(e) => {
+            eprintln!("{} {}", "error:".bright_red(), e);
+            false
+        }
     }
 }
 
@@ -30,41 +266,1159 @@ enum State {
     Stop,
 }
 
-fn process_line(rl: &mut Editor<()>, start_prompt: &str, overflow: &str) -> State {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Paste {
+    #[default]
+    Lenient,
+    Strict,
+}
+
+// whether a closing bracket typed at the terminal steps over the same closer
+// after the cursor, see `pair::step_over`; nothing else about brackets is paired
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum AutoPair {
+    #[default]
+    On,
+    Off,
+}
+
+struct Entry {
+    input: String,
+    value: f64,
+    time: SystemTime,
+}
+
+#[derive(Default)]
+struct Session {
+    options: parse::Options,
+    env: parse::Context,
+    history: Vec<Entry>,
+    radix: format::Radix,
+    paste: Paste,
+    autopair: AutoPair,
+    // whether the variables are saved on exit, to be loaded at the next start
+    autosave: bool,
+    dual_frac: bool,
+    si: bool,
+    // whether results are always in scientific notation, not just when large
+    // or small, and whether its exponent is written with `E` rather than `e`
+    sci: bool,
+    upper_exp: bool,
+    // whether zeros after the point that `precision` pads with are dropped
+    trim_zeros: bool,
+    bool_display: bool,
+    // whether the last result was from a comparison, for `bool_display`
+    last_was_comparison: bool,
+    precision: Option<usize>,
+    // the last input that failed, kept for :retry until it succeeds
+    last_failed: Option<String>,
+}
+
+impl Session {
+    fn eval(&mut self, input: &str) -> Result<f64, parse::CalcErr> {
+        let statement = parse::parse_statement(input, &self.options, &self.env)?;
+        let previous = match &statement {
+            parse::Statement::Assign(name, _) if self.options.reassign == parse::Reassign::Warn => {
+                self.env.get(name).map(|val| (name.clone(), val))
+            }
+            _ => None,
+        };
+        let comparison = statement.is_comparison();
+        let val = statement.exec(&mut self.env)?;
+        self.last_was_comparison = comparison;
+        if let Some((name, old)) = previous {
+            eprintln!("note: '{}' was {}", name, format_decimal(self, old));
+        }
+        self.env.set(parse::ANS, val);
+        self.history.push(Entry {
+            input: input.trim().to_string(),
+            value: val,
+            time: SystemTime::now(),
+        });
+        Ok(val)
+    }
+
+    // retries a line that failed to parse as a snippet pasted from source code
+    fn eval_pasted<'a>(&mut self, input: &'a str) -> Option<(&'a str, f64)> {
+        if self.paste == Paste::Strict {
+            return None;
+        }
+        let fixed = strip_paste(input)?;
+        self.eval(fixed).ok().map(|val| (fixed, val))
+    }
+
+    fn find<'a>(&'a self, needle: &'a str) -> impl Iterator<Item = &'a Entry> {
+        self.history
+            .iter()
+            .filter(move |entry| entry.input.contains(needle))
+    }
+}
+
+// where the REPL reads its lines from, which is the terminal outside of tests
+trait Reader {
+    fn readline(&mut self, prompt: &str) -> rustyline::Result<String>;
+    fn add_history_entry(&mut self, line: &str);
+}
+
+impl Reader for Editor<()> {
+    fn readline(&mut self, prompt: &str) -> rustyline::Result<String> {
+        Editor::readline(self, prompt)
+    }
+
+    fn add_history_entry(&mut self, line: &str) {
+        Editor::add_history_entry(self, line);
+    }
+}
+
+// a line ending in `\` is continued on the next one, without the `\`, even when
+// what has been entered so far is already complete
+fn process_line(
+    rl: &mut impl Reader,
+    session: &mut Session,
+    start_prompt: &str,
+    overflow: &str,
+) -> State {
     let mut input = String::new();
+    let mut done = 0;
     let mut prompt = start_prompt;
     loop {
         match rl.readline(prompt) {
             Err(Interrupted) | Err(Eof) => return State::Stop,
             Err(e) => panic!("Error: {:?}", e),
             Ok(line) => {
+                if input.is_empty() {
+                    match classify(&line) {
+                        Line::Command(command) => {
+                            rl.add_history_entry(line.trim_end());
+                            return run_command(session, command);
+                        }
+                        Line::Comment => {
+                            rl.add_history_entry(line.trim_end());
+                            return State::Continue;
+                        }
+                        Line::Expr => {}
+                    }
+                }
+                if let Some(head) = line.trim_end().strip_suffix('\\') {
+                    input.push_str(head);
+                    prompt = overflow;
+                    continue;
+                }
                 input.push_str(&line);
                 if input.is_empty() {
                     break;
                 }
-                match parse::eval(&input) {
-                    Ok(val) => {
-                        println!("{}", val);
+                match run_statements(session, &input, &mut done) {
+                    Ok(()) => break,
+                    Err(parse::CalcErr::Lex(e)) => {
+                        let pasted = if done == 0 {
+                            session.eval_pasted(&input)
+                        } else {
+                            None
+                        };
+                        match pasted {
+                            Some((fixed, val)) => {
+                                eprintln!("note: interpreted as '{}'", fixed);
+                                print_result(session, val);
+                            }
+                            None => print_error_message(&input, e.span, e.message),
+                        }
                         break;
                     }
-                    Err(parse::CalcErr::Lex(e)) => {
-                        print_error_message(&input, e);
+                    Err(parse::CalcErr::Incomplete { .. }) => prompt = overflow,
+                    Err(e @ (parse::CalcErr::Math { .. } | parse::CalcErr::TooDeep { .. })) => {
+                        print_calc_error(&input, e);
                         break;
                     }
-                    Err(parse::CalcErr::Incomplete) => prompt = overflow,
                 };
             }
         }
     }
-    rl.add_history_entry(input);
+    rl.add_history_entry(input.trim_end());
     State::Continue
 }
 
-fn print_error_message(input: &str, e: lex::LexErr) {
+// strips the parts of a statement copied out of source code that rcalc doesn't
+// accept: one trailing `;` and a leading `let` before an assignment. Anything
+// else, such as `let mut` or a `;` between statements, is left alone.
+fn strip_paste(line: &str) -> Option<&str> {
+    let line = line.trim();
+    let mut fixed = line.strip_suffix(';').unwrap_or(line).trim_end();
+    if let Some(rest) = fixed.strip_prefix("let") {
+        if rest.starts_with(char::is_whitespace) {
+            let rest = rest.trim_start();
+            let (name, value) = rest.split_once('=')?;
+            let name = name.trim_end();
+            let is_name = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !is_name || value.starts_with('=') {
+                return None;
+            }
+            fixed = rest;
+        }
+    }
+    if fixed.len() == line.len() || fixed.is_empty() || fixed.contains(';') {
+        return None;
+    }
+    Some(fixed)
+}
+
+// runs the `;` separated statements of `input`, skipping the first `done` chars
+// which were run before the input was continued on another line, and printing
+// each result in turn. `done` is moved past each statement that succeeds.
+fn run_statements(
+    session: &mut Session,
+    input: &str,
+    done: &mut usize,
+) -> Result<(), parse::CalcErr> {
+    let result = run_remaining_statements(session, input, done);
+    if let Err(e) = &result {
+        if !matches!(e, parse::CalcErr::Incomplete { .. }) {
+            session.last_failed = Some(input.trim().to_string());
+        }
+    }
+    result
+}
+
+fn run_remaining_statements(
+    session: &mut Session,
+    input: &str,
+    done: &mut usize,
+) -> Result<(), parse::CalcErr> {
+    let skipped = *done;
+    let rest = &input[input
+        .char_indices()
+        .nth(skipped)
+        .map_or(input.len(), |(i, _)| i)..];
+    let statements = parse::split_statements(rest).map_err(|e| parse::at_offset(e, skipped))?;
+    for (offset, statement) in statements {
+        let val = session
+            .eval(statement)
+            .map_err(|e| parse::statement_err(e, rest, offset, statement))
+            .map_err(|e| parse::at_offset(e, skipped))?;
+        print_result(session, val);
+        *done = skipped + offset + statement.chars().count() + 1;
+    }
+    Ok(())
+}
+
+fn split_word(input: &str) -> (&str, &str) {
+    let input = input.trim();
+    let (word, rest) = input.split_at(input.find(char::is_whitespace).unwrap_or(input.len()));
+    (word, rest.trim_start())
+}
+
+enum Line<'a> {
+    Command(&'a str),
+    Comment,
+    Expr,
+}
+
+// lines starting with `:` are commands for the REPL rather than expressions,
+// and lines starting with `#` are only comments, as in an annotated session
+fn classify(line: &str) -> Line<'_> {
+    let line = line.trim();
+    match line.strip_prefix(':') {
+        Some(command) => Line::Command(command),
+        None if line.starts_with('#') => Line::Comment,
+        None => Line::Expr,
+    }
+}
+
+const HELP: &str = "\
+operators:  + - * / // % mod ^ ** ! ( ) |x| and comparisons < <= > >= == !=
+            bitwise & | xor << >> on integers
+functions:  sqrt cbrt sin cos tan asin acos atan ln log log10 exp abs sigmoid
+            log(base, x) min(a, b, ...) max gcd lcm ilog(x, base) sigfig(x, figures)
+            lerp(a, b, t) dist(x1, y1, x2, y2) wavg(v1, w1, ...)
+constants:  pi e
+variables:  x = <expr>, with ans (or _) holding the previous result
+commands:   :help :quit :vars :retry :find <text> :export <file.csv> :precision <digits>
+            :hex :oct :bin :dec :deg :rad :bench <iterations> <expr> :words <expr>
+            :mod <expr> <modulus> :bits <expr> :hexfloat <expr>
+            :set {neg-binding|primes|constants|paste|autopair|autosave|dual-frac|
+                  si|sci|exp-char|trim-zeros|bool-display|reassign} <value>
+            (autopair only steps over a ) ] or } typed just before the same closer)";
+
+// the variables assigned so far, in name order
+fn vars(session: &Session) -> Vec<(&str, f64)> {
+    let mut vars: Vec<_> = session
+        .env
+        .iter()
+        .filter(|(name, _)| *name != parse::ANS)
+        .collect();
+    vars.sort_by(|a, b| a.0.cmp(b.0));
+    vars
+}
+
+fn run_command(session: &mut Session, command: &str) -> State {
+    let (name, args) = split_word(command);
+    match name {
+        "help" => println!("{}", HELP),
+        "quit" => return State::Stop,
+        "precision" => {
+            if let Err(msg) = precision(session, args) {
+                eprintln!("{}", msg);
+            }
+        }
+        "retry" => match session.last_failed.take() {
+            Some(input) => {
+                if let Err(e) = run_statements(session, &input, &mut 0) {
+                    print_calc_error(&input, e);
+                }
+            }
+            None => eprintln!("nothing to retry"),
+        },
+        "vars" => {
+            for (name, val) in vars(session) {
+                println!("{} = {}", name, format_decimal(session, val));
+            }
+        }
+        "bench" => bench(session, args),
+        "mod" => modulo(session, args),
+        "words" | "bits" | "hexfloat" if args.is_empty() => {
+            eprintln!("usage: :{} <expression>", name)
+        }
+        "words" => show(session, args, format::words),
+        "bits" => show(session, args, |val| Ok(format::bits(val))),
+        "hexfloat" => show(session, args, |val| Ok(format::hexfloat(val))),
+        "dec" => session.radix = format::Radix::Dec,
+        "hex" => session.radix = format::Radix::Hex,
+        "oct" => session.radix = format::Radix::Oct,
+        "bin" => session.radix = format::Radix::Bin,
+        "deg" => session.options.angle = parse::Angle::Degrees,
+        "rad" => session.options.angle = parse::Angle::Radians,
+        "find" if args.is_empty() => eprintln!("usage: :find <text>"),
+        "find" => {
+            for entry in session.find(args) {
+                println!("{} = {}", entry.input, format_decimal(session, entry.value));
+            }
+        }
+        "export" if args.is_empty() => eprintln!("usage: :export <file.csv>"),
+        "export" => {
+            if let Err(e) = export(&session.history, args) {
+                eprintln!("could not export to '{}': {}", args, e);
+            }
+        }
+        "set" => {
+            if let Err(msg) = set(session, args) {
+                eprintln!("{}", msg);
+            }
+        }
+        _ => eprintln!("unknown command ':{}'", name),
+    }
+    State::Continue
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// one row per result, numbered from 1, with the time in seconds since the epoch
+fn write_csv(history: &[Entry], out: &mut impl Write) -> io::Result<()> {
+    writeln!(out, "index,input,value,time")?;
+    for (i, entry) in history.iter().enumerate() {
+        let time = entry
+            .time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_secs());
+        writeln!(
+            out,
+            "{},{},{},{}",
+            i + 1,
+            csv_field(&entry.input),
+            entry.value,
+            time
+        )?;
+    }
+    Ok(())
+}
+
+fn export(history: &[Entry], path: &str) -> io::Result<()> {
+    write_csv(history, &mut File::create(path)?)
+}
+
+// `off` goes back to printing as many digits as are needed
+fn precision(session: &mut Session, args: &str) -> Result<(), &'static str> {
+    session.precision = match args {
+        "off" => None,
+        digits => match digits.parse() {
+            Ok(digits) if digits <= format::MAX_PRECISION => Some(digits),
+            _ => return Err("usage: :precision {0..100|off}"),
+        },
+    };
+    Ok(())
+}
+
+fn set(session: &mut Session, args: &str) -> Result<(), &'static str> {
+    match split_word(args) {
+        ("neg-binding", "loose") => session.options.neg_binding = parse::NegBinding::Loose,
+        ("neg-binding", "tight") => session.options.neg_binding = parse::NegBinding::Tight,
+        ("neg-binding", _) => return Err("usage: :set neg-binding {loose|tight}"),
+        ("primes", "on") => session.options.primes = true,
+        ("primes", "off") => session.options.primes = false,
+        ("primes", _) => return Err("usage: :set primes {on|off}"),
+        ("constants", "math") => session.options.physics = false,
+        ("constants", "physics") => session.options.physics = true,
+        ("constants", _) => return Err("usage: :set constants {math|physics}"),
+        ("dual-frac", "on") => session.dual_frac = true,
+        ("dual-frac", "off") => session.dual_frac = false,
+        ("dual-frac", _) => return Err("usage: :set dual-frac {on|off}"),
+        ("si", "on") => session.si = true,
+        ("si", "off") => session.si = false,
+        ("si", _) => return Err("usage: :set si {on|off}"),
+        ("sci", "on") => session.sci = true,
+        ("sci", "off") => session.sci = false,
+        ("sci", _) => return Err("usage: :set sci {on|off}"),
+        ("exp-char", "e") => session.upper_exp = false,
+        ("exp-char", "E") => session.upper_exp = true,
+        ("exp-char", _) => return Err("usage: :set exp-char {e|E}"),
+        ("trim-zeros", "on") => session.trim_zeros = true,
+        ("trim-zeros", "off") => session.trim_zeros = false,
+        ("trim-zeros", _) => return Err("usage: :set trim-zeros {on|off}"),
+        ("bool-display", "on") => session.bool_display = true,
+        ("bool-display", "off") => session.bool_display = false,
+        ("bool-display", _) => return Err("usage: :set bool-display {on|off}"),
+        ("max-depth", depth) => match depth.parse() {
+            Ok(depth) if depth > 0 && depth <= parse::DEPTH_LIMIT => {
+                session.options.max_depth = depth
+            }
+            _ => return Err("usage: :set max-depth <integer from 1 to 2048>"),
+        },
+        ("reassign", "silent") => session.options.reassign = parse::Reassign::Silent,
+        ("reassign", "warn") => session.options.reassign = parse::Reassign::Warn,
+        ("reassign", "error") => session.options.reassign = parse::Reassign::Error,
+        ("reassign", _) => return Err("usage: :set reassign {silent|warn|error}"),
+        ("paste", "lenient") => session.paste = Paste::Lenient,
+        ("paste", "strict") => session.paste = Paste::Strict,
+        ("paste", _) => return Err("usage: :set paste {lenient|strict}"),
+        ("autopair", "on") => session.autopair = AutoPair::On,
+        ("autopair", "off") => session.autopair = AutoPair::Off,
+        ("autosave", "on") => session.autosave = true,
+        ("autosave", "off") => session.autosave = false,
+        ("autosave", _) => return Err("usage: :set autosave {on|off}"),
+        ("autopair", _) => {
+            return Err("usage: :set autopair {on|off}, which only steps over closers")
+        }
+        _ => return Err("unknown setting"),
+    }
+    Ok(())
+}
+
+const MAX_BENCH_ITERATIONS: u32 = 100_000_000;
+
+fn parse_bench_args(args: &str) -> Result<(u32, &str), &'static str> {
+    let (count, expr) = split_word(args);
+    let count = count
+        .parse()
+        .map_err(|_| "usage: :bench <iterations> <expression>")?;
+    if count == 0 || count > MAX_BENCH_ITERATIONS {
+        return Err("iterations must be between 1 and 100000000");
+    }
+    match expr {
+        "" => Err("missing expression to benchmark"),
+        expr => Ok((count, expr)),
+    }
+}
+
+fn bench(session: &Session, args: &str) {
+    let (count, input) = match parse_bench_args(args) {
+        Ok(x) => x,
+        Err(msg) => return eprintln!("{}", msg),
+    };
+    let expr = match parse::parse_with(input, &session.options, &session.env) {
+        Ok(expr) => expr,
+        Err(e) => return print_calc_error(input, e),
+    };
+    if let Err(e) = expr.eval(&session.env) {
+        return print_calc_error(input, e);
+    }
+
+    let start = Instant::now();
+    for _ in 0..count {
+        let _ = black_box(black_box(&expr).eval(&session.env));
+    }
+    let total = start.elapsed();
+    println!(
+        "{} iterations in {:?} ({:?} per iteration)",
+        count,
+        total,
+        total / count
+    );
+}
+
+// prints the value of `input` as `format` writes it, leaving the history and
+// `ans` as they were
+fn show(session: &Session, input: &str, format: impl Fn(f64) -> Result<String, &'static str>) {
+    match parse::parse_with(input, &session.options, &session.env)
+        .and_then(|expr| expr.eval(&session.env))
+    {
+        Ok(val) => match format(val) {
+            Ok(text) => println!("{}", text),
+            Err(msg) => eprintln!("{} {}", "error:".bright_red(), msg),
+        },
+        Err(e) => print_calc_error(input, e),
+    }
+}
+
+// `:mod <expression> <modulus>`, where the modulus is the last word
+fn modulo(session: &Session, args: &str) {
+    let (input, modulus) = match args.trim().rsplit_once(char::is_whitespace) {
+        Some((input, modulus)) => (input.trim(), modulus),
+        None => return eprintln!("usage: :mod <expression> <modulus>"),
+    };
+    let parse = |input| parse::parse_with(input, &session.options, &session.env);
+    let modulus = match parse(modulus).and_then(|m| m.eval(&session.env)) {
+        Ok(m) if m >= 1.0 && m.fract() == 0.0 && m < u64::MAX as f64 => m as u64,
+        Ok(_) => return eprintln!("modulus must be a positive integer"),
+        Err(e) => return print_calc_error(modulus, e),
+    };
+    match parse(input).and_then(|expr| expr.eval_mod(&session.env, modulus)) {
+        // a residue too big for an f64 to hold exactly is printed in full
+        Ok(val) if val as f64 as u64 != val => println!("{}", val),
+        Ok(val) => println!("{}", format_decimal(session, val as f64)),
+        Err(e) => print_calc_error(input, e),
+    }
+}
+
+fn format_decimal(session: &Session, val: f64) -> String {
+    let text = if session.si {
+        format::format_si(val, session.precision)
+    } else if session.sci {
+        format::format_sci(val, session.precision)
+    } else {
+        format::format_result(val, session.precision)
+    };
+    let text = if session.upper_exp {
+        format::upper_exponent(&text)
+    } else {
+        text
+    };
+    let text = if session.trim_zeros {
+        format::trim_zeros(&text)
+    } else {
+        text
+    };
+    match format::fraction(val) {
+        Some(frac) if session.dual_frac => format!("{}  ({})", text, frac),
+        _ => text,
+    }
+}
+
+fn print_result(session: &Session, val: f64) {
+    if session.bool_display && session.last_was_comparison {
+        return println!("{}", val != 0.0);
+    }
+    if session.radix == format::Radix::Dec {
+        return println!("{}", format_decimal(session, val));
+    }
+    match format::format_radix(val, session.radix) {
+        Ok(s) => println!("{}", s),
+        Err(msg) => {
+            println!("{}", format_decimal(session, val));
+            eprintln!("note: {}, showing decimal", msg);
+        }
+    }
+}
+
+fn print_calc_error(input: &str, e: parse::CalcErr) {
+    match e.span() {
+        Some(span) => print_error_message(input, span, e.message()),
+        None => eprintln!("{} {}", "error:".bright_red(), e),
+    }
+}
+
+// spans count chars, which are turned into the columns they are displayed in
+// so the carets stay under the right place when the input has characters that
+// are more than one byte like `×` or two columns wide like `１`; a whole token
+// is underlined, so `1 + foo` marks all of `foo`
+fn error_lines(input: &str, span: lex::Span) -> (String, String) {
     let error_indent = 2;
-    eprintln!("\n{}", " ".repeat(error_indent) + input);
+    let byte = |pos| {
+        input
+            .char_indices()
+            .nth(pos)
+            .map_or(input.len(), |(i, _)| i)
+    };
+    let (start, end) = (byte(span.start), byte(span.end).max(byte(span.start)));
+    let column = input[..start].width();
+    let width = input[start..end].width().max(1);
+    (
+        " ".repeat(error_indent) + input,
+        format!(
+            "{}{} ",
+            " ".repeat(column + error_indent),
+            "^".repeat(width)
+        ),
+    )
+}
+
+fn print_error_message(input: &str, span: lex::Span, msg: &str) {
+    let (line, caret) = error_lines(input, span);
+    eprintln!("\n{}", line);
+    eprintln!("{}{}", caret.bright_red(), msg);
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    pub fn ans_holds_last_result() {
+        let mut session = Session::default();
+        assert_eq!(
+            Err(parse::CalcErr::Lex(lex::LexErr::spanning(
+                4..7,
+                "no previous result"
+            ))),
+            session.eval("1 + ans")
+        );
+        assert_eq!(Ok(1024.0), session.eval("2^10"));
+        assert_eq!(Ok(512.0), session.eval("ans / 2"));
+        assert_eq!(Ok(256.0), session.eval("_ / 2"));
+        assert!(session.eval("ans / ").is_err());
+        assert!(session.eval("ans + y").is_err());
+        assert_eq!(Ok(256.0), session.eval("ans"));
+        assert_eq!(Ok(3.0), session.eval("x = 3"));
+        assert_eq!(Ok(259.0), session.eval("x + 256"));
+    }
+
+    #[test]
+    pub fn variables_persist_between_lines() {
+        let mut session = Session::default();
+        assert_eq!(Ok(7.0), session.eval("x = 3 + 4"));
+        assert_eq!(Ok(14.0), session.eval("x * 2"));
+        assert_eq!(Ok(1.0), session.eval("x = 1"));
+        assert_eq!(Ok(2.0), session.eval("x * 2"));
+        assert_eq!(Some(1.0), session.env.get("x"));
+        assert_eq!(
+            Err(parse::CalcErr::Lex(lex::LexErr::new(
+                0,
+                "unknown identifier"
+            ))),
+            session.eval("y")
+        );
+    }
+
+    #[test]
+    pub fn statements_run_in_order() {
+        let mut session = Session::default();
+        let mut done = 0;
+        assert_eq!(
+            Ok(()),
+            run_statements(&mut session, "x = 2; x * 3;", &mut done)
+        );
+        assert_eq!(Some(6.0), session.env.get(parse::ANS));
+        assert_eq!(2, session.history.len());
+    }
+
+    // lines to read in order, recording the prompts they were read with
+    #[derive(Default)]
+    struct Script {
+        lines: Vec<&'static str>,
+        prompts: Vec<String>,
+        history: Vec<String>,
+    }
+
+    impl Reader for Script {
+        fn readline(&mut self, prompt: &str) -> rustyline::Result<String> {
+            self.prompts.push(prompt.to_string());
+            match self.lines.len() {
+                0 => Err(Eof),
+                _ => Ok(self.lines.remove(0).to_string()),
+            }
+        }
+
+        fn add_history_entry(&mut self, line: &str) {
+            self.history.push(line.to_string());
+        }
+    }
+
+    #[test]
+    pub fn backslash_continues_the_line() {
+        let mut session = Session::default();
+        let mut script = Script {
+            lines: vec!["1 + \\", "2 \\  ", "* 3", "4"],
+            ..Script::default()
+        };
+        process_line(&mut script, &mut session, ">>> ", "... ");
+        assert_eq!(Some(7.0), session.env.get(parse::ANS));
+        assert_eq!(vec![">>> ", "... ", "... "], script.prompts);
+        assert_eq!(vec!["1 + 2 * 3"], script.history);
+
+        process_line(&mut script, &mut session, ">>> ", "... ");
+        assert_eq!(Some(4.0), session.env.get(parse::ANS));
+    }
+
+    #[test]
+    pub fn continued_statements_are_not_run_twice() {
+        let mut session = Session::default();
+        let mut done = 0;
+        let mut input = String::from("x = 2; x *");
+        assert_eq!(
+            Err(parse::CalcErr::Incomplete {
+                span: 10..11,
+                msg: parse::INCOMPLETE_EXPR
+            }),
+            run_statements(&mut session, &input, &mut done)
+        );
+        assert_eq!(6, done);
+        assert_eq!(1, session.history.len());
+
+        input.push_str(" 5; y");
+        assert_eq!(
+            Err(parse::CalcErr::Lex(lex::LexErr::new(
+                14,
+                "unknown identifier"
+            ))),
+            run_statements(&mut session, &input, &mut done)
+        );
+        assert_eq!(Some(10.0), session.env.get(parse::ANS));
+        assert_eq!(2, session.history.len());
+    }
+
+    #[test]
+    pub fn lines_are_classified_as_commands_or_expressions() {
+        assert!(matches!(classify(":help"), Line::Command("help")));
+        assert!(matches!(
+            classify("  :set primes on "),
+            Line::Command("set primes on")
+        ));
+        assert!(matches!(classify(":"), Line::Command("")));
+        assert!(matches!(classify("1 + 2"), Line::Expr));
+        assert!(matches!(classify("x = 1 :"), Line::Expr));
+        assert!(matches!(classify(""), Line::Expr));
+        assert!(matches!(classify("  # tax rates"), Line::Comment));
+        assert!(matches!(classify("1.2 # tax rate"), Line::Expr));
+    }
+
+    #[test]
+    pub fn words_leaves_history_and_ans_alone() {
+        let mut session = Session::default();
+        assert_eq!(Ok(2.0), session.eval("1 + 1"));
+        run_command(&mut session, "words 6 * 7");
+        assert_eq!(1, session.history.len());
+        assert_eq!(Some(2.0), session.env.get(parse::ANS));
+    }
+
+    #[test]
+    pub fn quit_stops_the_session() {
+        let mut session = Session::default();
+        assert!(matches!(run_command(&mut session, "quit"), State::Stop));
+        assert!(matches!(run_command(&mut session, "help"), State::Continue));
+        assert!(matches!(
+            run_command(&mut session, "frobnicate"),
+            State::Continue
+        ));
+    }
+
+    #[test]
+    pub fn vars_lists_assigned_variables_in_order() {
+        let mut session = Session::default();
+        assert!(vars(&session).is_empty());
+        session.eval("y = 2").unwrap();
+        session.eval("x = 1").unwrap();
+        session.eval("x = 3").unwrap();
+        session.eval("x + y").unwrap();
+        assert_eq!(vec![("x", 3.0), ("y", 2.0)], vars(&session));
+    }
+
+    #[test]
+    pub fn retry_reruns_the_last_failed_input() {
+        let mut session = Session::default();
+        assert!(run_statements(&mut session, "x + 1", &mut 0).is_err());
+        assert_eq!(Some("x + 1"), session.last_failed.as_deref());
+        assert_eq!(Ok(()), run_statements(&mut session, "x = 5", &mut 0));
+        assert_eq!(Some("x + 1"), session.last_failed.as_deref());
+
+        run_command(&mut session, "retry");
+        assert_eq!(Some(6.0), session.env.get(parse::ANS));
+        assert_eq!(None, session.last_failed);
+    }
+
+    #[test]
+    pub fn retry_keeps_inputs_that_fail_again() {
+        let mut session = Session::default();
+        run_command(&mut session, "retry");
+        assert!(run_statements(&mut session, "1/0", &mut 0).is_err());
+        run_command(&mut session, "retry");
+        assert_eq!(Some("1/0"), session.last_failed.as_deref());
+        assert!(run_statements(&mut session, "2 *", &mut 0).is_err());
+        assert_eq!(Some("1/0"), session.last_failed.as_deref());
+    }
+
+    #[test]
+    pub fn find_searches_past_inputs() {
+        let mut session = Session::default();
+        session.eval("sqrt(16)").unwrap();
+        session.eval("2 + 2").unwrap();
+        session.eval("sqrt(4) * 3").unwrap();
+        session.eval("sqrt(").unwrap_err();
+
+        let found: Vec<_> = session
+            .find("sqrt")
+            .map(|entry| (entry.input.as_str(), entry.value))
+            .collect();
+        assert_eq!(vec![("sqrt(16)", 4.0), ("sqrt(4) * 3", 6.0)], found);
+        assert_eq!(1, session.find("2 + ").count());
+        assert_eq!(0, session.find("cos").count());
+    }
+
+    #[test]
+    pub fn caret_is_aligned_after_unicode_operators() {
+        let input = "3 × 4 ÷ 2 −* 1";
+        let span = match parse::eval(input) {
+            Err(parse::CalcErr::Lex(e)) => e.span,
+            e => panic!("expected an error, got {:?}", e),
+        };
+        assert_eq!(11..12, span);
+        let (line, caret) = error_lines(input, span);
+        let column = caret.chars().position(|c| c == '^').unwrap();
+        assert_eq!(Some('*'), line.chars().nth(column));
+        assert_eq!(Ok(6.0), parse::eval("3 × 4 ÷ 2"));
+        assert_eq!(Ok(1.0), parse::eval("3 − 2 · 1"));
+    }
+
+    #[test]
+    pub fn carets_underline_the_whole_token() {
+        let span = parse::eval("1 + foobar").unwrap_err().span().unwrap();
+        assert_eq!(
+            ("  1 + foobar".to_string(), "      ^^^^^^ ".to_string()),
+            error_lines("1 + foobar", span)
+        );
+        assert_eq!("    ^ ", error_lines("1 +", 2..2).1);
+    }
+
+    #[test]
+    pub fn carets_are_placed_by_display_width() {
+        assert_eq!(
+            ("  １ + $".to_string(), "  ^^ ".to_string()),
+            error_lines("１ + $", 0..1)
+        );
+        assert_eq!("       ^ ", error_lines("１ + $", 4..5).1);
+        assert_eq!("      ^ ", error_lines("é + $", 4..5).1);
+        assert_eq!("      ^^^ ", error_lines("2 × foo", 4..7).1);
+        // just past the end, as for an incomplete input
+        assert_eq!("            ^ ", error_lines("１２ ÷ 0 +", 8..9).1);
+    }
+
+    #[test]
+    pub fn csv_fields_are_quoted_when_needed() {
+        assert_eq!("1 + 2", csv_field("1 + 2"));
+        assert_eq!("\"wavg(1, 2)\"", csv_field("wavg(1, 2)"));
+        assert_eq!("\"a \"\"b\"\"\"", csv_field("a \"b\""));
+    }
+
+    #[test]
+    pub fn export_writes_history_as_csv() {
+        let mut session = Session::default();
+        session.eval("1 + 2").unwrap();
+        session.eval("1 / 0").unwrap_err();
+        session.eval("lerp(0, 10, 0.5)").unwrap();
+
+        let path = std::env::temp_dir().join(format!("rcalc-export-{}.csv", std::process::id()));
+        run_command(&mut session, &format!("export {}", path.display()));
+        let csv = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let rows: Vec<Vec<&str>> = csv
+            .lines()
+            .map(|line| line.rsplitn(3, ',').collect())
+            .collect();
+        assert_eq!(3, rows.len());
+        assert_eq!(vec!["time", "value", "index,input"], rows[0]);
+        assert_eq!(vec!["3", "1,1 + 2"], rows[1][1..]);
+        assert_eq!(vec!["5", "2,\"lerp(0, 10, 0.5)\""], rows[2][1..]);
+        assert!(rows[1][0].parse::<u64>().unwrap() > 0);
+    }
+
+    #[test]
+    pub fn saved_variables_load_back() {
+        let mut session = Session::default();
+        session.eval("rate = 1 / 3").unwrap();
+        session.eval("n = -12").unwrap();
+        let path = std::env::temp_dir().join(format!("rcalc-vars-{}", std::process::id()));
+        save_vars(&session, &path).unwrap();
+
+        let mut loaded = Session::default();
+        loaded.eval("n = 5").unwrap();
+        load_vars(&mut loaded, &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(vec![("n", -12.0), ("rate", 1.0 / 3.0)], vars(&loaded));
+        assert!(load_vars(&mut loaded, &path).is_err());
+    }
+
+    #[test]
+    pub fn export_to_unwritable_path_fails() {
+        let mut session = Session::default();
+        session.eval("1").unwrap();
+        let path = std::env::temp_dir()
+            .join("rcalc-missing-dir")
+            .join("out.csv");
+        assert!(export(&session.history, path.to_str().unwrap()).is_err());
+        run_command(&mut session, &format!("export {}", path.display()));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    pub fn radix_commands_persist_until_dec() {
+        let mut session = Session::default();
+        assert_eq!(format::Radix::Dec, session.radix);
+        run_command(&mut session, "hex");
+        assert_eq!(format::Radix::Hex, session.radix);
+        session.eval("1 + 1").unwrap();
+        assert_eq!(format::Radix::Hex, session.radix);
+        run_command(&mut session, "bin");
+        assert_eq!(format::Radix::Bin, session.radix);
+        run_command(&mut session, "dec");
+        assert_eq!(format::Radix::Dec, session.radix);
+    }
+
+    #[test]
+    pub fn angle_commands_switch_trig_units() {
+        let mut session = Session::default();
+        assert_eq!(Ok(1.0), session.eval("sin(pi/2)"));
+        run_command(&mut session, "deg");
+        assert_eq!(Ok(1.0), session.eval("sin(90)"));
+        assert_eq!(Ok(90.0), session.eval("asin(1)"));
+        run_command(&mut session, "rad");
+        assert_eq!(Ok(1.0), session.eval("sin(pi/2)"));
+    }
+
+    #[test]
+    pub fn reassigning_follows_the_setting() {
+        let mut session = Session::default();
+        session.eval("x = 1").unwrap();
+        assert_eq!(Ok(2.0), session.eval("x = 2"));
+
+        set(&mut session, "reassign warn").unwrap();
+        assert_eq!(Ok(3.0), session.eval("x = 3"));
+
+        set(&mut session, "reassign error").unwrap();
+        assert_eq!(
+            Err(parse::CalcErr::Lex(lex::LexErr::new(
+                0,
+                parse::ALREADY_ASSIGNED
+            ))),
+            session.eval("x = 4")
+        );
+        assert_eq!(Some(3.0), session.env.get("x"));
+        assert_eq!(Ok(5.0), session.eval("y = 5"));
+        assert_eq!(Ok(6.0), session.eval("x + 3"));
+        assert!(set(&mut session, "reassign loudly").is_err());
+    }
 
-    let (pos, msg) = e;
-    let x = format!("{}^ ", " ".repeat(pos + error_indent));
-    eprintln!("{}{}", x.bright_red(), msg);
+    #[test]
+    pub fn set_neg_binding() {
+        let mut session = Session::default();
+        assert_eq!(Ok(()), set(&mut session, "neg-binding tight"));
+        assert_eq!(parse::NegBinding::Tight, session.options.neg_binding);
+        assert_eq!(Ok(()), set(&mut session, " neg-binding   loose "));
+        assert_eq!(parse::NegBinding::Loose, session.options.neg_binding);
+        assert!(set(&mut session, "neg-binding sideways").is_err());
+        assert!(set(&mut session, "colour blue").is_err());
+    }
+
+    #[test]
+    pub fn set_primes() {
+        let mut session = Session::default();
+        assert!(!session.options.primes);
+        assert_eq!(Ok(()), set(&mut session, "primes on"));
+        assert!(session.options.primes);
+        assert_eq!(Ok(()), set(&mut session, "primes off"));
+        assert!(!session.options.primes);
+        assert!(set(&mut session, "primes").is_err());
+    }
+
+    #[test]
+    pub fn pasted_snippets_are_stripped() {
+        assert_eq!(Some("x = (a*b) / c"), strip_paste("x = (a*b) / c;"));
+        assert_eq!(
+            Some("total = price * qty"),
+            strip_paste("let total = price * qty")
+        );
+        assert_eq!(Some("total = 2"), strip_paste("  let  total = 2 ; "));
+        assert_eq!(Some("1 + 2"), strip_paste("1 + 2;"));
+        assert_eq!(Some("letter = 2"), strip_paste("letter = 2;"));
+    }
+
+    #[test]
+    pub fn ambiguous_snippets_are_not_stripped() {
+        assert_eq!(None, strip_paste("1 + 2"));
+        assert_eq!(None, strip_paste(";"));
+        assert_eq!(None, strip_paste("x;;"));
+        assert_eq!(None, strip_paste("x = 1; y = 2"));
+        assert_eq!(None, strip_paste("let x;"));
+        assert_eq!(None, strip_paste("let mut x = 1;"));
+        assert_eq!(None, strip_paste("let x == 1"));
+        assert_eq!(None, strip_paste("let (a, b) = (1, 2)"));
+    }
+
+    #[test]
+    pub fn pasted_snippets_are_retried_unless_strict() {
+        let mut session = Session::default();
+        session.eval("price = 3").unwrap();
+        assert!(session.eval("let total = price * 4;").is_err());
+        assert_eq!(
+            Some(("total = price * 4", 12.0)),
+            session.eval_pasted("let total = price * 4;")
+        );
+        assert_eq!(Some(12.0), session.env.get("total"));
+        assert_eq!(None, session.eval_pasted("let total = missing;"));
+
+        assert_eq!(Ok(()), set(&mut session, "paste strict"));
+        assert_eq!(None, session.eval_pasted("total * 2;"));
+        assert!(set(&mut session, "paste always").is_err());
+    }
+
+    #[test]
+    pub fn autopair_can_be_turned_off() {
+        let mut session = Session::default();
+        assert_eq!(AutoPair::On, session.autopair);
+        assert_eq!(Ok(()), set(&mut session, "autopair off"));
+        assert_eq!(AutoPair::Off, session.autopair);
+        assert_eq!(Ok(()), set(&mut session, "autopair on"));
+        assert_eq!(AutoPair::On, session.autopair);
+        assert!(set(&mut session, "autopair yes").is_err());
+    }
+
+    #[test]
+    pub fn trim_zeros_shortens_fixed_precision() {
+        let mut session = Session::default();
+        assert_eq!(Ok(()), precision(&mut session, "3"));
+        assert_eq!("2.000", format_decimal(&session, 2.0));
+        assert_eq!(Ok(()), set(&mut session, "trim-zeros on"));
+        assert_eq!("2", format_decimal(&session, 2.0));
+        assert_eq!("0.25", format_decimal(&session, 0.25));
+        assert_eq!("0.333", format_decimal(&session, 1.0 / 3.0));
+        assert_eq!(Ok(()), set(&mut session, "trim-zeros off"));
+        assert_eq!("2.000", format_decimal(&session, 2.0));
+        assert!(set(&mut session, "trim-zeros yes").is_err());
+    }
+
+    #[test]
+    pub fn exp_char_sets_the_exponent_letter() {
+        let mut session = Session::default();
+        assert_eq!("1500", format_decimal(&session, 1500.0));
+        assert_eq!(Ok(()), set(&mut session, "sci on"));
+        assert_eq!("1.5e3", format_decimal(&session, 1500.0));
+        assert_eq!(Ok(()), set(&mut session, "exp-char E"));
+        assert_eq!("1.5E3", format_decimal(&session, 1500.0));
+        assert_eq!(Ok(()), set(&mut session, "sci off"));
+        assert_eq!("1500", format_decimal(&session, 1500.0));
+        assert_eq!("2.5E20", format_decimal(&session, 2.5e20));
+        assert_eq!(Ok(()), set(&mut session, "exp-char e"));
+        assert_eq!("2.5e20", format_decimal(&session, 2.5e20));
+        assert!(set(&mut session, "exp-char x").is_err());
+        assert!(set(&mut session, "sci yes").is_err());
+    }
+
+    #[test]
+    pub fn dual_frac_annotates_decimal_results() {
+        let mut session = Session::default();
+        assert_eq!("0.75", format_decimal(&session, 0.75));
+        assert_eq!(Ok(()), set(&mut session, "dual-frac on"));
+        let val = session.eval("3/4").unwrap();
+        assert_eq!("0.75  (3/4)", format_decimal(&session, val));
+        let val = session.eval("pi").unwrap();
+        assert_eq!(val.to_string(), format_decimal(&session, val));
+        assert_eq!("2", format_decimal(&session, 2.0));
+        assert_eq!(Ok(()), set(&mut session, "dual-frac off"));
+        assert_eq!("0.75", format_decimal(&session, 0.75));
+    }
+
+    #[test]
+    pub fn si_setting_prefixes_results() {
+        let mut session = Session::default();
+        assert_eq!(Ok(()), set(&mut session, "si on"));
+        let val = session.eval("4.7 / 1000").unwrap();
+        assert_eq!("4.7m", format_decimal(&session, val));
+        assert_eq!(Ok(()), set(&mut session, "si off"));
+        assert_eq!("0.0047", format_decimal(&session, val));
+        assert!(set(&mut session, "si yes").is_err());
+    }
+
+    #[test]
+    pub fn set_max_depth() {
+        let mut session = Session::default();
+        assert_eq!(parse::DEFAULT_MAX_DEPTH, session.options.max_depth);
+        assert_eq!(Ok(()), set(&mut session, "max-depth 3"));
+        assert_eq!(Ok(1.0), session.eval("(((1)))"));
+        assert_eq!(
+            Err(parse::CalcErr::TooDeep { span: 3..4 }),
+            session.eval("((((1))))")
+        );
+        assert!(set(&mut session, "max-depth 0").is_err());
+        assert!(set(&mut session, "max-depth deep").is_err());
+        assert_eq!(3, session.options.max_depth);
+        // no deeper than the parser thread's stack is known to fit
+        assert_eq!(Ok(()), set(&mut session, "max-depth 2048"));
+        assert_eq!(parse::DEPTH_LIMIT, session.options.max_depth);
+        assert!(set(&mut session, "max-depth 2049").is_err());
+        assert!(set(&mut session, "max-depth 100000000").is_err());
+    }
+
+    #[test]
+    pub fn leading_flags_are_split_from_the_expression() {
+        let args = |args: &[&str]| args.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let input = args(&["--max-depth", "2", "(1", "+", "1)"]);
+        let (flags, rest) = parse_flags(&input).unwrap();
+        assert_eq!(&input[2..], rest);
+        assert_eq!(2, Session::with_flags(&flags).options.max_depth);
+
+        let input = args(&["--precision", "3", "--max-depth", "5", "1"]);
+        let (flags, rest) = parse_flags(&input).unwrap();
+        assert_eq!(&input[4..], rest);
+        let session = Session::with_flags(&flags);
+        assert_eq!((Some(3), 5), (session.precision, session.options.max_depth));
+        assert!(parse_flags(&args(&["--precision", "-1", "1"])).is_err());
+
+        let input = args(&["--base", "bin", "10"]);
+        let (flags, _) = parse_flags(&input).unwrap();
+        assert_eq!(format::Radix::Bin, Session::with_flags(&flags).radix);
+        assert!(parse_flags(&args(&["--base", "2", "10"])).is_err());
+
+        let input = args(&["1", "--max-depth", "2"]);
+        assert_eq!(input, parse_flags(&input).unwrap().1);
+        assert!(parse_flags(&args(&["--max-depth"])).is_err());
+        assert!(parse_flags(&args(&["--max-depth", "x", "1"])).is_err());
+        let input = args(&["--5", "+", "1"]);
+        assert_eq!(input, parse_flags(&input).unwrap().1);
+    }
+
+    #[test]
+    pub fn precision_command() {
+        let mut session = Session::default();
+        assert_eq!("0.3333333333333333", format_decimal(&session, 1.0 / 3.0));
+        run_command(&mut session, "precision 4");
+        assert_eq!("0.3333", format_decimal(&session, 1.0 / 3.0));
+        assert!(precision(&mut session, "many").is_err());
+        assert!(precision(&mut session, "101").is_err());
+        assert_eq!(Some(4), session.precision);
+        run_command(&mut session, "precision off");
+        assert_eq!(None, session.precision);
+    }
+
+    #[test]
+    pub fn bench_args_split_count_from_expr() {
+        assert_eq!(
+            Ok((1000, "2 * (3 + 4)")),
+            parse_bench_args(" 1000 2 * (3 + 4)")
+        );
+        assert_eq!(Ok((1, "1")), parse_bench_args("1\t1"));
+    }
+
+    #[test]
+    pub fn bench_args_are_validated() {
+        assert!(parse_bench_args("").is_err());
+        assert!(parse_bench_args("lots 1 + 1").is_err());
+        assert!(parse_bench_args("-5 1 + 1").is_err());
+        assert!(parse_bench_args("0 1 + 1").is_err());
+        assert!(parse_bench_args("1000000000 1 + 1").is_err());
+        assert!(parse_bench_args("1000").is_err());
+    }
 }
@@ -1,11 +1,138 @@
 use std::env;
+use std::process;
+
+use rcalc::{defined_region, format_result, Region};
+
+const MAX_TABLE_ROWS: usize = 100_000;
+
+const TABLE_USAGE: &str =
+    "usage: rcalc --table <expr> --var <name> --from <number> --to <number> [--step <number>]";
+
+// the expression, its variable and the values it takes for `--table`, where
+// the values go from `--from` up to `--to` in steps of `--step`, 1 by default
+fn parse_table_args(args: &[String]) -> Result<(&str, &str, Vec<f64>), String> {
+    let (expr, mut rest) = match args.split_first() {
+        Some((expr, rest)) if !expr.starts_with("--") => (expr.as_str(), rest),
+        _ => return Err(TABLE_USAGE.to_string()),
+    };
+    let (mut var, mut from, mut to, mut step) = (None, None, None, 1.0);
+    while let [flag, value, tail @ ..] = rest {
+        let number = || {
+            value
+                .parse::<f64>()
+                .ok()
+                .filter(|n| n.is_finite())
+                .ok_or_else(|| format!("{}: expected a number", flag))
+        };
+        match flag.as_str() {
+            "--var" => var = Some(value.as_str()),
+            "--from" => from = Some(number()?),
+            "--to" => to = Some(number()?),
+            "--step" => step = number()?,
+            _ => return Err(TABLE_USAGE.to_string()),
+        }
+        rest = tail;
+    }
+    let (var, from, to) = match (rest, var, from, to) {
+        ([], Some(var), Some(from), Some(to)) => (var, from, to),
+        _ => return Err(TABLE_USAGE.to_string()),
+    };
+    if step <= 0.0 {
+        return Err("--step must be greater than 0".to_string());
+    }
+    if from > to {
+        return Err("--from must not be greater than --to".to_string());
+    }
+    // a little slack so that rounding in the division doesn't drop the last row
+    let rows = ((to - from) / step * (1.0 + 1e-12)).floor() + 1.0;
+    if rows > MAX_TABLE_ROWS as f64 {
+        return Err(format!(
+            "too many rows, at most {} are printed",
+            MAX_TABLE_ROWS
+        ));
+    }
+    // rounded to the places of `--from` and `--step`, so 0.1 * 3 is 0.3
+    let places = decimal_places(from).max(decimal_places(step));
+    let values = (0..rows as usize)
+        .map(|i| {
+            let x = from + i as f64 * step;
+            format!("{:.*}", places, x)
+                .parse()
+                .expect("a decimal parses")
+        })
+        .collect();
+    Ok((expr, var, values))
+}
+
+fn decimal_places(x: f64) -> usize {
+    x.to_string()
+        .split_once('.')
+        .map_or(0, |(_, fraction)| fraction.len())
+}
+
+// prints `var` and the expression for each value, separated by a tab, leaving
+// out the values at either end where the expression is undefined
+fn table(args: &[String]) -> i32 {
+    let (expr, var, values) = match parse_table_args(args) {
+        Ok(x) => x,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            return 2;
+        }
+    };
+    let results = match rcalc::parse::eval_each(expr, var, &values) {
+        Ok(results) => results,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return 1;
+        }
+    };
+    let region = defined_region(&results);
+    if let Some(note) = region.note(var, &values) {
+        eprintln!("note: {}", note);
+    }
+    let range = match region {
+        Region::Everywhere => 0..values.len(),
+        Region::Partly { range, .. } => range,
+        Region::Nowhere => return 1,
+    };
+    for (x, y) in values[range.clone()].iter().zip(&results[range]) {
+        let y = match y {
+            Ok(y) => format_result(*y, None),
+            Err(_) => "undefined".to_string(),
+        };
+        println!("{}\t{}", format_result(*x, None), y);
+    }
+    0
+}
 
 fn main() {
-    let args: String = env::args().skip(1).collect();
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args == ["--self-test"] {
+        let report = rcalc::self_test();
+        for failure in &report.failures {
+            eprintln!("{}", failure);
+        }
+        println!("{} passed, {} failed", report.passed, report.failures.len());
+        process::exit(if report.ok() { 0 } else { 1 });
+    }
+    if args.first().map(String::as_str) == Some("--table") {
+        process::exit(table(&args[1..]));
+    }
+    let (flags, args) = match rcalc::parse_flags(&args) {
+        Ok(x) => x,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            process::exit(2);
+        }
+    };
+    let args = args.join(" ");
     if args.is_empty() {
-        println!("\nWelcome to rcalc!\nYou can evaluate math expressions using + - * / % ^ ()\n");
-        rcalc::run();
+        eprintln!("\nWelcome to rcalc!\nYou can evaluate math expressions using + - * / % ^ ()\n");
+        rcalc::run(&flags);
     } else {
-        rcalc::compute(&args);
+        if !rcalc::compute(&args, &flags) {
+            process::exit(1);
+        }
     }
 }
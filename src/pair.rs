@@ -0,0 +1,107 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use rustyline::{
+    Cmd, ConditionalEventHandler, Event, EventContext, KeyCode, KeyEvent, Modifiers, Movement,
+    RepeatCount,
+};
+
+// the brackets that are paired; `|` both opens and closes, so it isn't
+const PAIRS: [(char, char); 3] = [('(', ')'), ('[', ']'), ('{', '}')];
+
+fn opener(close: char) -> Option<char> {
+    PAIRS
+        .iter()
+        .find(|(_, c)| *c == close)
+        .map(|(open, _)| *open)
+}
+
+/// Where the cursor, a byte offset into `line`, moves to when `typed` is a
+/// closing bracket typed right before the same closer, which it steps over
+/// rather than adding another, or `None` when the key is typed as usual. It
+/// only steps over when the line has as many of that closer as of its opener,
+/// so that `((|)` still gets its second `)`.
+///
+/// Closers are never inserted along with their opener, nor deleted along with
+/// it, since rustyline 8 runs a single command per key and none of them edit
+/// both sides of the cursor.
+pub(crate) fn step_over(line: &str, cursor: usize, typed: char) -> Option<usize> {
+    let open = opener(typed)?;
+    let count = |bracket| line.chars().filter(|&x| x == bracket).count();
+    if !line[cursor..].starts_with(typed) || count(open) != count(typed) {
+        return None;
+    }
+    Some(cursor + typed.len_utf8())
+}
+
+/// Steps over closers as they are typed at the terminal while its flag is set.
+pub(crate) struct PairHandler(pub Arc<AtomicBool>);
+
+impl PairHandler {
+    pub const KEYS: [KeyEvent; 3] = [
+        KeyEvent(KeyCode::Char(')'), Modifiers::NONE),
+        KeyEvent(KeyCode::Char(']'), Modifiers::NONE),
+        KeyEvent(KeyCode::Char('}'), Modifiers::NONE),
+    ];
+}
+
+impl ConditionalEventHandler for PairHandler {
+    fn handle(&self, evt: &Event, n: RepeatCount, _: bool, ctx: &EventContext) -> Option<Cmd> {
+        if !self.0.load(Ordering::Relaxed) || n != 1 {
+            return None;
+        }
+        let typed = match evt.get(0)? {
+            KeyEvent(KeyCode::Char(c), _) => *c,
+            _ => return None,
+        };
+        step_over(ctx.line(), ctx.pos(), typed)?;
+        Some(Cmd::Move(Movement::ForwardChar(1)))
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    // `|` marks the cursor in both the line typed into and the expected line
+    fn typed(line: &str, c: char) -> Option<String> {
+        let cursor = line.find('|').expect("line has a cursor");
+        let line = line.replacen('|', "", 1);
+        step_over(&line, cursor, c).map(|cursor| {
+            let mut line = line;
+            line.insert(cursor, '|');
+            line
+        })
+    }
+
+    #[test]
+    pub fn closers_step_over_their_match_when_balanced() {
+        for (open, close) in PAIRS.iter() {
+            let line = format!("{}|{}", open, close);
+            let expected = format!("{}{}|", open, close);
+            assert_eq!(Some(expected), typed(&line, *close));
+        }
+        assert_eq!(Some("sqrt(4)| + 1".to_string()), typed("sqrt(4|) + 1", ')'));
+        assert_eq!(Some("((1))|".to_string()), typed("((1)|)", ')'));
+        assert_eq!(Some("é(1)|".to_string()), typed("é(1|)", ')'));
+    }
+
+    #[test]
+    pub fn closers_are_inserted_when_one_is_missing() {
+        assert_eq!(None, typed("((1|)", ')'));
+        assert_eq!(None, typed("(1|", ')'));
+        assert_eq!(None, typed("(1| + 2)", ')'));
+        assert_eq!(None, typed("|", ')'));
+        // a different closer is never stepped over
+        assert_eq!(None, typed("[(|)]", ']'));
+        assert_eq!(None, typed("(|]", ')'));
+    }
+
+    #[test]
+    pub fn other_keys_are_left_alone() {
+        for c in &['(', '[', '{', '1', 'x', '+', '|', ' ', '×'] {
+            assert_eq!(None, typed("(|)", *c), "{}", c);
+            assert_eq!(None, typed("|", *c), "{}", c);
+        }
+    }
+}
@@ -1,271 +1,3472 @@
+use std::collections::HashMap;
 use std::convert::From;
+use std::f64::consts;
+use std::fmt;
 use std::iter::Peekable;
+use std::sync::Arc;
 
-use crate::lex;
+use crate::lex::{self, LexErr};
 use lex::Token::*;
 use Operator::*;
 
-#[derive(Debug, PartialEq, Eq)]
-enum Operator {
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Operator {
     Add,
     Sub,
     Mul,
     Div,
+    IntDiv,
     Mod,
+    EuclidMod,
     Pow,
     Neg,
+    Fact,
+    Abs,
+    Less,
+    LessEq,
+    Greater,
+    GreaterEq,
+    Equal,
+    NotEqual,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
+}
+
+// `Clone`, `PartialEq` and `Debug` are written out below without recursion,
+// like `eval`, so that long inputs can't overflow the call stack
+pub enum Expr {
+    // operators and calls keep the span of their token, or of the start of the
+    // right operand for an implied multiplication, to point math errors at them
+    Unary(Operator, lex::Span, Box<Expr>),
+    Binary(Operator, lex::Span, Box<Expr>, Box<Expr>),
+    Call(String, lex::Span, Vec<Expr>),
+    // and names and numbers that of the name, or of the digits or constant
+    // they were read from
+    Var(String, lex::Span),
+    Num(f64, lex::Span),
+}
+
+#[derive(Debug)]
+pub(crate) enum Statement {
+    Expr(Expr),
+    Assign(String, Expr),
+}
+
+type Resolver = Arc<dyn Fn(&str) -> Option<f64> + Send + Sync>;
+
+/// The variables an expression can use, by name, and a resolver for names
+/// that aren't one of them.
+#[derive(Default, Clone)]
+pub struct Context {
+    vars: HashMap<String, f64>,
+    resolver: Option<Resolver>,
+}
+
+impl fmt::Debug for Context {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Context")
+            .field("vars", &self.vars)
+            .field("resolver", &self.resolver.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
+
+// contexts with resolvers are only equal when they share the same one
+impl PartialEq for Context {
+    fn eq(&self, other: &Self) -> bool {
+        let same_resolver = match (&self.resolver, &other.resolver) {
+            (None, None) => true,
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        };
+        self.vars == other.vars && same_resolver
+    }
+}
+
+impl Context {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, name: &str, value: f64) -> Self {
+        self.set(name, value);
+        self
+    }
+
+    /// Sets `name` to `value`, giving the value it had before if it was set.
+    pub fn set(&mut self, name: &str, value: f64) -> Option<f64> {
+        self.vars.insert(name.to_string(), value)
+    }
+
+    pub fn get(&self, name: &str) -> Option<f64> {
+        self.vars.get(name).copied()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.vars.contains_key(name)
+    }
+
+    /// The variables in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, f64)> {
+        self.vars
+            .iter()
+            .map(|(name, &value)| (name.as_str(), value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.vars.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vars.is_empty()
+    }
+
+    /// Has `resolver` give the values of names that aren't a function, a
+    /// constant or one of the variables, when an expression is parsed. It is
+    /// asked about each name once per parse, and the value it gives is used
+    /// where the name is, so the name isn't a variable of the expression.
+    /// When it gives `None` the name is an unknown identifier.
+    pub fn set_resolver(&mut self, resolver: impl Fn(&str) -> Option<f64> + Send + Sync + 'static) {
+        self.resolver = Some(Arc::new(resolver));
+    }
+}
+
+const EMPTY_STATEMENT: &str = "expected a statement before ';'";
+const EXPECTED_OPERAND: &str = "expected an operand";
+const EXPECTED_END: &str = "expected an operator or end of input";
+const EXPECTED_ARG_END: &str = "expected ',' or ')'";
+const EXPECTED_RPAREN: &str = "expected ')'";
+const EXPECTED_RBRACKET: &str = "expected ']'";
+const EXPECTED_RBRACE: &str = "expected '}'";
+const UNCLOSED_PAREN: &str = "expected ')' to close the '(' opened here";
+const UNCLOSED_BRACKET: &str = "expected ']' to close the '[' opened here";
+const UNCLOSED_BRACE: &str = "expected '}' to close the '{' opened here";
+const EXPECTED_BAR: &str = "expected '|'";
+const UNCLOSED_BAR: &str = "expected '|' to close the '|' opened here";
+const UNKNOWN_FUNCTION: &str = "unknown function";
+const UNKNOWN_IDENTIFIER: &str = "unknown identifier";
+const EXPECTED_LPAREN: &str = "expected '('";
+const MISMATCHED_BRACKET: &str = "closing bracket doesn't match the opening one";
+const RESERVED_NAME: &str = "name is reserved";
+const NO_PREVIOUS_RESULT: &str = "no previous result";
+pub(crate) const ALREADY_ASSIGNED: &str = "variable is already assigned";
+pub const UNKNOWN_VARIABLE: &str = "unknown variable";
+pub const NOT_UNARY: &str = "operator needs two operands";
+pub const NOT_BINARY: &str = "operator needs one operand";
+pub const DIVISION_BY_ZERO: &str = "division by zero";
+pub const NOT_FINITE: &str = "result is not finite";
+pub const FACTORIAL_DOMAIN: &str = "factorial needs a non-negative integer";
+pub const GCD_DOMAIN: &str = "gcd and lcm need integers";
+pub const SIGFIG_DOMAIN: &str = "sigfig needs a whole number of figures of at least 1";
+pub const WAVG_DOMAIN: &str = "wavg weights sum to zero";
+pub const ILOG_DOMAIN: &str = "ilog needs a positive integer and an integer base of at least 2";
+pub const NOT_AN_INTEGER: &str = "only integers can be reduced by a modulus";
+pub const NEGATIVE_EXPONENT: &str = "exponent must be a non-negative integer under a modulus";
+pub const INCOMPLETE_EXPR: &str = "incomplete expression";
+pub const TOO_DEEP: &str = "expression is nested too deeply";
+pub const BITWISE_DOMAIN: &str = "bitwise operators need integers";
+pub const SHIFT_DOMAIN: &str = "shifts need an amount from 0 to 63";
+
+pub(crate) const ANS: &str = "ans";
+// a name used as an operator, `a mod b`
+const MOD: &str = "mod";
+// and `a xor b`, since `^` is already a power
+const XOR: &str = "xor";
+
+// `[ ]` and `{ }` group like `( )`, for expressions pasted from elsewhere, but
+// only `( )` surround the arguments of a function
+fn closing_bracket(open: &lex::Token) -> Option<lex::Token> {
+    match open {
+        LParen => Some(RParen),
+        LBracket => Some(RBracket),
+        LBrace => Some(RBrace),
+        _ => None,
+    }
+}
+
+fn expected_closing(close: &lex::Token) -> &'static str {
+    match close {
+        RBracket => EXPECTED_RBRACKET,
+        RBrace => EXPECTED_RBRACE,
+        _ => EXPECTED_RPAREN,
+    }
+}
+
+fn unclosed(close: &lex::Token) -> &'static str {
+    match close {
+        RBracket => UNCLOSED_BRACKET,
+        RBrace => UNCLOSED_BRACE,
+        _ => UNCLOSED_PAREN,
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CalcErr {
+    Lex(lex::LexErr),
+    // the input ran out, with the chars just past its end or of the bracket
+    // that was never closed
+    Incomplete { span: lex::Span, msg: &'static str },
+    Math { span: lex::Span, msg: &'static str },
+    // the bracket, sign, power or call that went past the nesting limit
+    TooDeep { span: lex::Span },
+}
+
+impl From<LexErr> for CalcErr {
+    fn from(e: LexErr) -> Self {
+        CalcErr::Lex(e)
+    }
+}
+
+impl CalcErr {
+    /// The chars (counting from 0) that the error points at, which is just
+    /// past the end of an input that is missing something there.
+    pub fn span(&self) -> Option<lex::Span> {
+        match self {
+            CalcErr::Lex(e) => Some(e.span.clone()),
+            CalcErr::Incomplete { span, .. }
+            | CalcErr::Math { span, .. }
+            | CalcErr::TooDeep { span } => Some(span.clone()),
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            CalcErr::Lex(e) => e.message,
+            CalcErr::Incomplete { msg, .. } | CalcErr::Math { msg, .. } => msg,
+            CalcErr::TooDeep { .. } => TOO_DEEP,
+        }
+    }
+}
+
+impl fmt::Display for CalcErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.span() {
+            Some(span) => write!(f, "{} at position {}", self.message(), span.start),
+            None => write!(f, "{}", self.message()),
+        }
+    }
+}
+
+impl std::error::Error for CalcErr {}
+
+type ExprResult = Result<Expr, CalcErr>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum NegBinding {
+    #[default]
+    Loose,
+    Tight,
+}
+
+// what assigning to a variable that already has a value does
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum Reassign {
+    #[default]
+    Silent,
+    Warn,
+    Error,
+}
+
+// the unit that trig functions take, and inverse ones give
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum Angle {
+    #[default]
+    Radians,
+    Degrees,
+}
+
+pub(crate) const DEFAULT_MAX_DEPTH: usize = 256;
+// the most that `max_depth` can be set to, which parsing fits into a stack of
+// `STACK_SIZE` even in an unoptimised build, at around 13KB a level
+pub(crate) const DEPTH_LIMIT: usize = 2048;
+pub(crate) const STACK_SIZE: usize = 64 << 20;
+
+/// The settings that change how input is parsed, which the REPL sets with
+/// `:set`. Outside of it there are only the defaults.
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub(crate) neg_binding: NegBinding,
+    pub(crate) primes: bool,
+    pub(crate) physics: bool,
+    // how deeply parentheses, signs, powers and calls may nest
+    pub(crate) max_depth: usize,
+    pub(crate) reassign: Reassign,
+    pub(crate) angle: Angle,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            neg_binding: NegBinding::default(),
+            primes: false,
+            physics: false,
+            max_depth: DEFAULT_MAX_DEPTH,
+            reassign: Reassign::default(),
+            angle: Angle::default(),
+        }
+    }
+}
+
+// exact or CODATA 2018 values, in SI units
+fn physical_constant(name: &str) -> Option<f64> {
+    match name {
+        "c" => Some(299_792_458.0),         // speed of light, m/s
+        "h" => Some(6.626_070_15e-34),      // Planck constant, J s
+        "hbar" => Some(1.054_571_817e-34),  // reduced Planck constant, J s
+        "N_A" => Some(6.022_140_76e23),     // Avogadro constant, 1/mol
+        "k_B" => Some(1.380_649e-23),       // Boltzmann constant, J/K
+        "q_e" => Some(1.602_176_634e-19),   // elementary charge, C
+        "m_e" => Some(9.109_383_701_5e-31), // electron mass, kg
+        "G" => Some(6.674_30e-11),          // gravitational constant, m^3/(kg s^2)
+        "g_0" => Some(9.806_65),            // standard gravity, m/s^2
+        _ => None,
+    }
+}
+
+fn constant(name: &str) -> Option<f64> {
+    match name {
+        "pi" => Some(consts::PI),
+        "e" => Some(consts::E),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Function {
+    Unary(fn(f64) -> f64),
+    Fixed(usize, fn(&[f64]) -> f64),
+    // from the first to the second number of arguments, not all of which
+    // are allowed
+    Between(usize, usize, fn(&[f64]) -> Result<f64, &'static str>),
+    Pairs(fn(&[f64]) -> Result<f64, &'static str>),
+}
+
+impl Function {
+    fn accepts(self, arg_count: usize) -> bool {
+        match self {
+            Function::Unary(_) => arg_count == 1,
+            Function::Fixed(n, _) => arg_count == n,
+            Function::Between(min, max, _) => (min..=max).contains(&arg_count),
+            Function::Pairs(_) => arg_count > 0 && arg_count.is_multiple_of(2),
+        }
+    }
+
+    fn apply(self, args: &[f64]) -> Result<f64, &'static str> {
+        match self {
+            Function::Unary(f) => Ok(f(args[0])),
+            Function::Fixed(_, f) => Ok(f(args)),
+            Function::Between(_, _, f) | Function::Pairs(f) => f(args),
+        }
+    }
+}
+
+// whole numbers are multiplied out so that factorials stay exact for as long as
+// f64 allows; anything above 170! overflows
+fn factorial(x: f64) -> Result<f64, &'static str> {
+    if x < 0.0 || x.fract() != 0.0 {
+        return Err(FACTORIAL_DOMAIN);
+    }
+    if x > 170.0 {
+        return Ok(f64::INFINITY);
+    }
+    Ok((2..=x as u32).fold(1.0, |acc, k| acc * k as f64))
+}
+
+// log(x) is in base 10; 2 and 10 are done with their own functions, which
+// are exact for powers of the base where dividing the logs isn't always
+fn log(args: &[f64]) -> Result<f64, &'static str> {
+    let val = match *args {
+        [x] | [10.0, x] => x.log10(),
+        [2.0, x] => x.log2(),
+        [base, x] => x.ln() / base.ln(),
+        _ => unreachable!("log takes 1 or 2 arguments"),
+    };
+    Ok(val)
+}
+
+fn min(args: &[f64]) -> Result<f64, &'static str> {
+    Ok(args.iter().copied().fold(f64::INFINITY, f64::min))
+}
+
+fn max(args: &[f64]) -> Result<f64, &'static str> {
+    Ok(args.iter().copied().fold(f64::NEG_INFINITY, f64::max))
+}
+
+fn integers(args: &[f64]) -> Result<impl Iterator<Item = f64> + '_, &'static str> {
+    if args.iter().all(|x| x.fract() == 0.0) {
+        Ok(args.iter().map(|x| x.abs()))
+    } else {
+        Err(GCD_DOMAIN)
+    }
+}
+
+fn gcd_of(mut a: f64, mut b: f64) -> f64 {
+    while b != 0.0 {
+        let r = a % b;
+        a = b;
+        b = r;
+    }
+    a
+}
+
+fn gcd(args: &[f64]) -> Result<f64, &'static str> {
+    Ok(integers(args)?.fold(0.0, gcd_of))
+}
+
+// the lcm of anything with 0 is 0
+fn lcm(args: &[f64]) -> Result<f64, &'static str> {
+    Ok(integers(args)?.fold(1.0, |a, b| match gcd_of(a, b) {
+        0.0 => 0.0,
+        g => a / g * b,
+    }))
+}
+
+// rounded to `figures` significant figures by moving those figures in front of
+// the point and back, dividing rather than multiplying by a fraction like 0.01
+// which would add error of its own; an f64 never has more than 17 of them
+fn sigfig(args: &[f64]) -> Result<f64, &'static str> {
+    let (x, figures) = (args[0], args[1]);
+    if figures < 1.0 || figures.fract() != 0.0 {
+        return Err(SIGFIG_DOMAIN);
+    }
+    if x == 0.0 || figures > 17.0 {
+        return Ok(x);
+    }
+    let shift = figures as i32 - 1 - x.abs().log10().floor() as i32;
+    let val = if shift > f64::MAX_10_EXP {
+        // tiny and subnormal values need a scale too large for an f64, so
+        // they are scaled up in two steps
+        let (first, second) = (10f64.powi(shift / 2), 10f64.powi(shift - shift / 2));
+        (x * first * second).round() / second / first
+    } else if shift >= 0 {
+        let scale = 10f64.powi(shift);
+        (x * scale).round() / scale
+    } else {
+        let scale = 10f64.powi(-shift);
+        (x / scale).round() * scale
+    };
+    Ok(val)
+}
+
+// the floor of the logarithm, counted by dividing so that exact powers like
+// 1000 in base 10 aren't rounded down to the power below
+fn ilog(args: &[f64]) -> Result<f64, &'static str> {
+    let (mut x, base) = (args[0], args[1]);
+    if x < 1.0 || x.fract() != 0.0 || base < 2.0 || base.fract() != 0.0 {
+        return Err(ILOG_DOMAIN);
+    }
+    let mut log = 0.0;
+    while x >= base {
+        x = (x / base).floor();
+        log += 1.0;
+    }
+    Ok(log)
 }
 
-#[derive(Debug)]
-enum Expr {
-    Unary(Operator, Box<Expr>),
-    Binary(Operator, Box<Expr>, Box<Expr>),
-    Num(f64),
-}
+fn lerp(args: &[f64]) -> f64 {
+    let (a, b, t) = (args[0], args[1], args[2]);
+    a + (b - a) * t
+}
+
+// exp only ever sees a non-positive argument, so it can't overflow
+fn sigmoid(x: f64) -> f64 {
+    if x >= 0.0 {
+        1.0 / (1.0 + (-x).exp())
+    } else {
+        let e = x.exp();
+        e / (1.0 + e)
+    }
+}
+
+fn dist(args: &[f64]) -> f64 {
+    let (x1, y1, x2, y2) = (args[0], args[1], args[2], args[3]);
+    (x2 - x1).hypot(y2 - y1)
+}
+
+// `_` is shorthand for the previous result
+fn is_ans(name: &str) -> bool {
+    name == ANS || name == "_"
+}
+
+// alternating value, weight pairs
+fn wavg(args: &[f64]) -> Result<f64, &'static str> {
+    let (sum, weights) = args.chunks(2).fold((0.0, 0.0), |(sum, weights), vw| {
+        (sum + vw[0] * vw[1], weights + vw[1])
+    });
+    if weights == 0.0 {
+        return Err(WAVG_DOMAIN);
+    }
+    Ok(sum / weights)
+}
+
+fn scaled(x: Expr, span: lex::Span, factor: f64) -> Expr {
+    Expr::Binary(
+        Mul,
+        span.clone(),
+        Box::new(x),
+        Box::new(Expr::Num(factor, span)),
+    )
+}
+
+// in degrees the argument of a trig function is converted to radians, and the
+// result of an inverse one is converted back
+fn in_angle_unit(angle: Angle, name: String, span: lex::Span, mut args: Vec<Expr>) -> Expr {
+    match (angle, name.as_str()) {
+        (Angle::Degrees, "sin" | "cos" | "tan") => {
+            let arg = args.pop().expect("trig functions take one argument");
+            let arg = scaled(arg, span.clone(), consts::PI / 180.0);
+            Expr::Call(name, span, vec![arg])
+        }
+        (Angle::Degrees, "asin" | "acos" | "atan") => scaled(
+            Expr::Call(name, span.clone(), args),
+            span,
+            180.0 / consts::PI,
+        ),
+        _ => Expr::Call(name, span, args),
+    }
+}
+
+// a function and the message for calling it with the wrong number of arguments
+fn function(name: &str) -> Option<(Function, &'static str)> {
+    use Function::*;
+
+    const ANY: usize = usize::MAX;
+    let f = match name {
+        "sqrt" => (Unary(f64::sqrt), "sqrt takes 1 argument"),
+        "cbrt" => (Unary(f64::cbrt), "cbrt takes 1 argument"),
+        "sin" => (Unary(f64::sin), "sin takes 1 argument"),
+        "cos" => (Unary(f64::cos), "cos takes 1 argument"),
+        "tan" => (Unary(f64::tan), "tan takes 1 argument"),
+        "asin" => (Unary(f64::asin), "asin takes 1 argument"),
+        "acos" => (Unary(f64::acos), "acos takes 1 argument"),
+        "atan" => (Unary(f64::atan), "atan takes 1 argument"),
+        "ln" => (Unary(f64::ln), "ln takes 1 argument"),
+        "log" => (
+            Between(1, 2, log),
+            "log takes 1 argument, or 2 as log(base, x)",
+        ),
+        "log10" => (Unary(f64::log10), "log10 takes 1 argument"),
+        "abs" => (Unary(f64::abs), "abs takes 1 argument"),
+        "exp" => (Unary(f64::exp), "exp takes 1 argument"),
+        "sigmoid" => (Unary(sigmoid), "sigmoid takes 1 argument"),
+        "min" => (Between(2, ANY, min), "min takes 2 or more arguments"),
+        "max" => (Between(2, ANY, max), "max takes 2 or more arguments"),
+        "gcd" => (Between(2, ANY, gcd), "gcd takes 2 or more arguments"),
+        "lcm" => (Between(2, ANY, lcm), "lcm takes 2 or more arguments"),
+        "ilog" => (Between(2, 2, ilog), "ilog takes 2 arguments"),
+        "sigfig" => (Between(2, 2, sigfig), "sigfig takes 2 arguments"),
+        "lerp" => (Fixed(3, lerp), "lerp takes 3 arguments"),
+        "dist" => (Fixed(4, dist), "dist takes 4 arguments"),
+        "wavg" => (Pairs(wavg), "wavg takes pairs of a value and a weight"),
+        _ => return None,
+    };
+    Some(f)
+}
+
+// checked at every step so that an overflow can't be hidden by a later
+// operation, as in 1/10^1000
+fn finite(val: f64) -> Result<f64, &'static str> {
+    if val.is_finite() {
+        Ok(val)
+    } else {
+        Err(NOT_FINITE)
+    }
+}
+
+fn apply_unary(op: Operator, x: f64) -> Result<f64, &'static str> {
+    match op {
+        Neg => Ok(-x),
+        Fact => factorial(x),
+        Abs => Ok(x.abs()),
+        _ => Err(NOT_UNARY),
+    }
+}
+
+// an integer-valued operand of a bitwise operator
+fn bits(x: f64) -> Result<i64, &'static str> {
+    // 2^63 is exact as an f64, unlike i64::MAX
+    if x.fract() == 0.0 && x >= i64::MIN as f64 && x < -(i64::MIN as f64) {
+        Ok(x as i64)
+    } else {
+        Err(BITWISE_DOMAIN)
+    }
+}
+
+// the bits of `x` and `y` combined, where a shift by more than the width of an
+// i64 is an error rather than wrapping around like `i64::wrapping_shl`
+fn apply_bitwise(op: Operator, x: f64, y: f64) -> Result<f64, &'static str> {
+    let (x, y) = (bits(x)?, bits(y)?);
+    let shift = || {
+        if (0..64).contains(&y) {
+            Ok(y as u32)
+        } else {
+            Err(SHIFT_DOMAIN)
+        }
+    };
+    let val = match op {
+        BitAnd => x & y,
+        BitOr => x | y,
+        BitXor => x ^ y,
+        ShiftLeft => x << shift()?,
+        // keeps the sign, so -16 >> 2 is -4
+        ShiftRight => x >> shift()?,
+        _ => unreachable!("{:?} is not a bitwise operator", op),
+    };
+    Ok(val as f64)
+}
+
+fn apply_binary(op: Operator, x: f64, y: f64) -> Result<f64, &'static str> {
+    let val = match op {
+        Add => x + y,
+        Sub => x - y,
+        Mul => x * y,
+        Div | IntDiv | Mod | EuclidMod if y == 0.0 => return Err(DIVISION_BY_ZERO),
+        Div => x / y,
+        // rounds towards negative infinity, so -7 // 2 is -4
+        IntDiv => (x / y).floor(),
+        // `%` keeps the sign of x, `mod` is never negative, so -7 mod 3 is 2
+        Mod => x % y,
+        EuclidMod => x.rem_euclid(y),
+        Pow => x.powf(y),
+        Less => f64::from(x < y),
+        LessEq => f64::from(x <= y),
+        Greater => f64::from(x > y),
+        GreaterEq => f64::from(x >= y),
+        Equal => f64::from(x == y),
+        NotEqual => f64::from(x != y),
+        BitAnd | BitOr | BitXor | ShiftLeft | ShiftRight => return apply_bitwise(op, x, y),
+        Neg | Fact | Abs => return Err(NOT_BINARY),
+    };
+    Ok(val)
+}
+
+// a step of the evaluation, which is done with an explicit stack rather than
+// recursion so that long chains like 1+1+...+1 can't overflow the call stack
+enum Step<'a> {
+    Visit(&'a Expr),
+    Apply(&'a Expr),
+}
+
+impl Expr {
+    /// The value of the expression with the variables in `env`. Trees built
+    /// by hand are checked as they are evaluated, so a variable missing from
+    /// `env`, an unknown function, the wrong number of arguments or an
+    /// operator with the wrong number of operands is an error at its span.
+    pub fn eval(&self, env: &Context) -> Result<f64, CalcErr> {
+        use Expr::*;
+
+        let mut steps = vec![Step::Visit(self)];
+        let mut values = Vec::new();
+        while let Some(step) = steps.pop() {
+            let (span, val) = match step {
+                Step::Visit(Num(x, _)) => {
+                    values.push(*x);
+                    continue;
+                }
+                Step::Visit(Var(name, span)) => match env.get(name) {
+                    Some(x) => {
+                        values.push(x);
+                        continue;
+                    }
+                    None => (span, Err(UNKNOWN_VARIABLE)),
+                },
+                Step::Visit(expr) => {
+                    steps.push(Step::Apply(expr));
+                    match expr {
+                        Unary(_, _, x) => steps.push(Step::Visit(x)),
+                        Binary(_, _, x, y) => {
+                            steps.push(Step::Visit(y));
+                            steps.push(Step::Visit(x));
+                        }
+                        Call(_, _, args) => steps.extend(args.iter().rev().map(Step::Visit)),
+                        Num(..) | Var(..) => unreachable!(),
+                    }
+                    continue;
+                }
+                Step::Apply(Unary(op, span, _)) => {
+                    let x = values.pop().expect("operand was evaluated");
+                    (span, apply_unary(*op, x))
+                }
+                Step::Apply(Binary(op, span, _, _)) => {
+                    let y = values.pop().expect("operand was evaluated");
+                    let x = values.pop().expect("operand was evaluated");
+                    (span, apply_binary(*op, x, y))
+                }
+                Step::Apply(Call(name, span, args)) => {
+                    let args = values.split_off(values.len() - args.len());
+                    let val = match function(name) {
+                        Some((f, _)) if f.accepts(args.len()) => f.apply(&args),
+                        Some((_, usage)) => Err(usage),
+                        None => Err(UNKNOWN_FUNCTION),
+                    };
+                    (span, val)
+                }
+                Step::Apply(Num(..) | Var(..)) => unreachable!(),
+            };
+            match val.and_then(finite) {
+                Ok(val) => values.push(val),
+                Err(msg) => {
+                    return Err(CalcErr::Math {
+                        span: span.clone(),
+                        msg,
+                    })
+                }
+            }
+        }
+        Ok(values.pop().expect("expression has a value"))
+    }
+}
+
+// an integer value as the least non-negative number congruent to it
+fn reduce(x: f64, modulus: u64) -> Result<u64, &'static str> {
+    if !x.is_finite() || x.fract() != 0.0 {
+        return Err(NOT_AN_INTEGER);
+    }
+    Ok(x.rem_euclid(modulus as f64) as u64)
+}
+
+// square and multiply, which never needs more than the modulus squared
+fn mod_pow(base: u64, mut exponent: u64, modulus: u64) -> u64 {
+    let (mut base, modulus) = (u128::from(base), u128::from(modulus));
+    let mut result = 1 % modulus;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = result * base % modulus;
+        }
+        base = base * base % modulus;
+        exponent >>= 1;
+    }
+    result as u64
+}
+
+impl Expr {
+    /// Whether the value is a truth value, which is when the outermost
+    /// operator is a comparison.
+    pub fn is_comparison(&self) -> bool {
+        matches!(
+            self,
+            Expr::Binary(Less | LessEq | Greater | GreaterEq | Equal | NotEqual, ..)
+        )
+    }
+
+    /// Evaluates modulo `modulus`, reducing after every `+`, `-`, `*` and `^`
+    /// so that large powers like 3^1000 stay exact. Exponents are evaluated as
+    /// they are, and anything else is evaluated as usual and then reduced.
+    pub fn eval_mod(&self, env: &Context, modulus: u64) -> Result<u64, CalcErr> {
+        use Expr::*;
+
+        let mut steps = vec![Step::Visit(self)];
+        let mut values = Vec::new();
+        while let Some(step) = steps.pop() {
+            let (span, val) = match step {
+                Step::Visit(expr @ Binary(Add | Sub | Mul, _, x, y)) => {
+                    steps.push(Step::Apply(expr));
+                    steps.push(Step::Visit(y));
+                    steps.push(Step::Visit(x));
+                    continue;
+                }
+                Step::Visit(expr @ (Binary(Pow, _, x, _) | Unary(Neg, _, x))) => {
+                    steps.push(Step::Apply(expr));
+                    steps.push(Step::Visit(x));
+                    continue;
+                }
+                Step::Visit(expr) => {
+                    let x = expr.eval(env)?;
+                    match expr {
+                        Unary(_, span, _)
+                        | Binary(_, span, _, _)
+                        | Call(_, span, _)
+                        | Var(_, span)
+                        | Num(_, span) => (span, reduce(x, modulus)),
+                    }
+                }
+                Step::Apply(Unary(_, span, _)) => {
+                    let x = values.pop().expect("operand was evaluated");
+                    (span, Ok((modulus - x) % modulus))
+                }
+                Step::Apply(Binary(Pow, span, _, y)) => {
+                    let x = values.pop().expect("operand was evaluated");
+                    let val = match y.eval(env)? {
+                        y if y >= 0.0 && y.fract() == 0.0 && y < u64::MAX as f64 => {
+                            Ok(mod_pow(x, y as u64, modulus))
+                        }
+                        _ => Err(NEGATIVE_EXPONENT),
+                    };
+                    (span, val)
+                }
+                Step::Apply(Binary(op, span, _, _)) => {
+                    let y = values.pop().expect("operand was evaluated");
+                    let x = values.pop().expect("operand was evaluated");
+                    let (x, y, m) = (u128::from(x), u128::from(y), u128::from(modulus));
+                    let val = match op {
+                        Add => (x + y) % m,
+                        Sub => (x + m - y) % m,
+                        _ => x * y % m,
+                    };
+                    (span, Ok(val as u64))
+                }
+                Step::Apply(_) => unreachable!("only operators are applied"),
+            };
+            match val {
+                Ok(val) => values.push(val),
+                Err(msg) => {
+                    return Err(CalcErr::Math {
+                        span: span.clone(),
+                        msg,
+                    })
+                }
+            }
+        }
+        Ok(values.pop().expect("expression has a value"))
+    }
+}
+
+fn value(expr: &Expr) -> Option<f64> {
+    match expr {
+        Expr::Num(x, _) => Some(*x),
+        _ => None,
+    }
+}
+
+// `node` with its operands already simplified
+fn simplified(mut node: Expr) -> Expr {
+    use std::mem::replace;
+    use Expr::*;
+
+    let constant = match &node {
+        Unary(_, _, x) => value(x).is_some(),
+        Binary(_, _, x, y) => value(x).is_some() && value(y).is_some(),
+        Call(_, _, args) => args.iter().all(|arg| value(arg).is_some()),
+        Num(..) | Var(..) => false,
+    };
+    if constant {
+        return match (node.eval(&Context::new()), &node) {
+            // the value keeps the span of what it was folded from
+            (Ok(val), Unary(_, span, _) | Binary(_, span, ..) | Call(_, span, _)) => {
+                Num(val, span.clone())
+            }
+            _ => node,
+        };
+    }
+    let take = |x: &mut Box<Expr>| replace(&mut **x, Num(0.0, 0..0));
+    let identity = match &mut node {
+        Binary(Add, _, x, y) | Binary(Sub, _, x, y) if value(y) == Some(0.0) => Some(take(x)),
+        Binary(Add, _, x, y) if value(x) == Some(0.0) => Some(take(y)),
+        Binary(Mul, _, x, y) | Binary(Pow, _, x, y) if value(y) == Some(1.0) => Some(take(x)),
+        Binary(Mul, _, x, y) if value(x) == Some(1.0) => Some(take(y)),
+        Unary(Neg, _, x) => match &mut **x {
+            Unary(Neg, _, x) => Some(take(x)),
+            _ => None,
+        },
+        _ => None,
+    };
+    identity.unwrap_or(node)
+}
+
+impl Expr {
+    /// The same expression with its constant parts evaluated and `x + 0`,
+    /// `x - 0`, `x * 1`, `x ^ 1` and `--x` written as `x`, so that `x * (2 + 3)`
+    /// becomes `x * 5`. It evaluates to the same result, and parts that fail
+    /// to evaluate are kept so that they fail in the same way.
+    pub fn simplify(&self) -> Expr {
+        self.rebuild(simplified)
+    }
+
+    // a copy built from the leaves up, passing each node to `finish` once its
+    // operands are done
+    fn rebuild(&self, mut finish: impl FnMut(Expr) -> Expr) -> Expr {
+        use Expr::*;
+
+        let mut steps = vec![Step::Visit(self)];
+        let mut done = Vec::new();
+        while let Some(step) = steps.pop() {
+            match step {
+                Step::Visit(Num(x, span)) => done.push(finish(Num(*x, span.clone()))),
+                Step::Visit(Var(name, span)) => done.push(finish(Var(name.clone(), span.clone()))),
+                Step::Visit(expr) => {
+                    steps.push(Step::Apply(expr));
+                    match expr {
+                        Unary(_, _, x) => steps.push(Step::Visit(x)),
+                        Binary(_, _, x, y) => {
+                            steps.push(Step::Visit(y));
+                            steps.push(Step::Visit(x));
+                        }
+                        Call(_, _, args) => steps.extend(args.iter().rev().map(Step::Visit)),
+                        Num(..) | Var(..) => unreachable!(),
+                    }
+                }
+                Step::Apply(expr) => {
+                    let node = match expr {
+                        Unary(op, span, _) => {
+                            let x = done.pop().expect("operand was rebuilt");
+                            Unary(*op, span.clone(), Box::new(x))
+                        }
+                        Binary(op, span, _, _) => {
+                            let y = done.pop().expect("operand was rebuilt");
+                            let x = done.pop().expect("operand was rebuilt");
+                            Binary(*op, span.clone(), Box::new(x), Box::new(y))
+                        }
+                        Call(name, span, args) => {
+                            let args = done.split_off(done.len() - args.len());
+                            Call(name.clone(), span.clone(), args)
+                        }
+                        Num(..) | Var(..) => unreachable!(),
+                    };
+                    done.push(finish(node));
+                }
+            }
+        }
+        done.pop().expect("expression was rebuilt")
+    }
+}
+
+impl Clone for Expr {
+    fn clone(&self) -> Self {
+        self.rebuild(|node| node)
+    }
+}
+
+impl PartialEq for Expr {
+    fn eq(&self, other: &Self) -> bool {
+        use Expr::*;
+
+        let mut pairs = vec![(self, other)];
+        while let Some(pair) = pairs.pop() {
+            match pair {
+                (Unary(a, s, x), Unary(b, t, y)) if a == b && s == t => pairs.push((x, y)),
+                (Binary(a, s, x, y), Binary(b, t, v, w)) if a == b && s == t => {
+                    pairs.push((y, w));
+                    pairs.push((x, v));
+                }
+                (Call(f, s, xs), Call(g, t, ys)) if f == g && s == t && xs.len() == ys.len() => {
+                    pairs.extend(xs.iter().zip(ys))
+                }
+                (Var(a, s), Var(b, t)) if a == b && s == t => {}
+                (Num(a, s), Num(b, t)) if a == b && s == t => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+/// Prints the tree as `#[derive(Debug)]` would on one line, as in
+/// `Binary(Add, 1..2, Num(1.0, 0..1), Var("x", 2..3))`.
+impl fmt::Debug for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Expr::*;
+
+        let mut stack = vec![Piece::Expr(self)];
+        while let Some(piece) = stack.pop() {
+            let expr = match piece {
+                Piece::Text(text) => {
+                    f.write_str(text)?;
+                    continue;
+                }
+                Piece::Expr(expr) => expr,
+            };
+            match expr {
+                Num(x, span) => write!(f, "Num({:?}, {:?})", x, span)?,
+                Var(name, span) => write!(f, "Var({:?}, {:?})", name, span)?,
+                Unary(op, span, x) => {
+                    write!(f, "Unary({:?}, {:?}, ", op, span)?;
+                    stack.extend([Piece::Text(")"), Piece::Expr(x)]);
+                }
+                Binary(op, span, x, y) => {
+                    write!(f, "Binary({:?}, {:?}, ", op, span)?;
+                    stack.extend([Piece::Text(")"), Piece::Expr(y), Piece::Text(", ")]);
+                    stack.push(Piece::Expr(x));
+                }
+                Call(name, span, args) => {
+                    write!(f, "Call({:?}, {:?}, [", name, span)?;
+                    stack.push(Piece::Text("])"));
+                    for (i, arg) in args.iter().enumerate().rev() {
+                        stack.push(Piece::Expr(arg));
+                        if i > 0 {
+                            stack.push(Piece::Text(", "));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// how tightly each kind of expression binds when printed, from bitwise or
+// up to numbers, names, calls and `|x|`
+const BIT_OR: u8 = 1;
+const BIT_XOR: u8 = 2;
+const BIT_AND: u8 = 3;
+const SHIFT: u8 = 4;
+const COMPARISON: u8 = 5;
+const SUM: u8 = 6;
+const PRODUCT: u8 = 7;
+const SIGNED: u8 = 8;
+const POWER: u8 = 9;
+const POSTFIX: u8 = 10;
+const PRIMARY: u8 = 11;
+
+fn binds(expr: &Expr) -> u8 {
+    match expr {
+        Expr::Binary(BitOr, ..) => BIT_OR,
+        Expr::Binary(BitXor, ..) => BIT_XOR,
+        Expr::Binary(BitAnd, ..) => BIT_AND,
+        Expr::Binary(ShiftLeft | ShiftRight, ..) => SHIFT,
+        Expr::Binary(Less | LessEq | Greater | GreaterEq | Equal | NotEqual, ..) => COMPARISON,
+        Expr::Binary(Add | Sub, ..) => SUM,
+        Expr::Binary(Pow, ..) => POWER,
+        Expr::Binary(..) => PRODUCT,
+        Expr::Unary(Neg, ..) => SIGNED,
+        Expr::Unary(Fact, ..) => POSTFIX,
+        Expr::Unary(..) | Expr::Call(..) | Expr::Var(..) | Expr::Num(..) => PRIMARY,
+    }
+}
+
+fn symbol(op: Operator) -> &'static str {
+    match op {
+        Add => " + ",
+        Sub | Neg => " - ",
+        Mul => " * ",
+        Div => " / ",
+        IntDiv => " // ",
+        Mod => " % ",
+        EuclidMod => " mod ",
+        Pow => "^",
+        Less => " < ",
+        LessEq => " <= ",
+        Greater => " > ",
+        GreaterEq => " >= ",
+        Equal => " == ",
+        NotEqual => " != ",
+        BitAnd => " & ",
+        BitOr => " | ",
+        BitXor => " xor ",
+        ShiftLeft => " << ",
+        ShiftRight => " >> ",
+        // as operators of two operands, which only a tree built by hand has
+        Fact => " ! ",
+        Abs => " abs ",
+    }
+}
+
+// one piece of the output, for printing without recursion
+enum Piece<'a> {
+    Expr(&'a Expr),
+    Text(&'a str),
+}
+
+/// Prints an expression so that it parses back to the same expression with
+/// the default options, using only the parentheses the order of operations
+/// needs: `2 * (3 + 4)`, `2^3^4` but `(2^3)^4`. Implied multiplications are
+/// written out with `*`.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Expr::*;
+
+        // `expr` in parentheses unless it binds at least as tightly as `min`,
+        // and a negative number always, since `-2` reads back as 2 negated
+        fn operand<'a>(pieces: &mut Vec<Piece<'a>>, expr: &'a Expr, min: u8) {
+            if binds(expr) < min || matches!(expr, Num(x, _) if x.is_sign_negative()) {
+                pieces.extend([Piece::Text("("), Piece::Expr(expr), Piece::Text(")")]);
+            } else {
+                pieces.push(Piece::Expr(expr));
+            }
+        }
+
+        let mut stack = vec![Piece::Expr(self)];
+        while let Some(piece) = stack.pop() {
+            let expr = match piece {
+                Piece::Text(text) => {
+                    f.write_str(text)?;
+                    continue;
+                }
+                Piece::Expr(expr) => expr,
+            };
+            let mut pieces = Vec::new();
+            match expr {
+                Num(x, _) => write!(f, "{}", x)?,
+                Var(name, _) => f.write_str(name)?,
+                Unary(Neg, _, x) => {
+                    pieces.push(Piece::Text("-"));
+                    operand(&mut pieces, x, SIGNED);
+                }
+                // a `|` for bitwise or would close the bars
+                Unary(Abs, _, x) => {
+                    pieces.push(Piece::Text("|"));
+                    operand(&mut pieces, x, BIT_XOR);
+                    pieces.push(Piece::Text("|"));
+                }
+                Unary(Fact, _, x) => {
+                    operand(&mut pieces, x, POSTFIX);
+                    pieces.push(Piece::Text("!"));
+                }
+                // only a tree built by hand has a unary operator that isn't one
+                Unary(op, _, x) => {
+                    pieces.push(Piece::Text(symbol(*op).trim_start()));
+                    operand(&mut pieces, x, PRIMARY);
+                }
+                // `^` groups to the right and everything else to the left
+                Binary(Pow, _, x, y) => {
+                    operand(&mut pieces, x, POSTFIX);
+                    pieces.push(Piece::Text("^"));
+                    operand(&mut pieces, y, SIGNED);
+                }
+                Binary(op, _, x, y) => {
+                    let level = binds(expr);
+                    operand(&mut pieces, x, level);
+                    pieces.push(Piece::Text(symbol(*op)));
+                    operand(&mut pieces, y, level + 1);
+                }
+                Call(name, _, args) => {
+                    pieces.extend([Piece::Text(name), Piece::Text("(")]);
+                    for (i, arg) in args.iter().enumerate() {
+                        if i > 0 {
+                            pieces.push(Piece::Text(", "));
+                        }
+                        pieces.push(Piece::Expr(arg));
+                    }
+                    pieces.push(Piece::Text(")"));
+                }
+            }
+            stack.extend(pieces.into_iter().rev());
+        }
+        Ok(())
+    }
+}
+
+// dropping is also done without recursion, for the same reason as `eval`
+impl Drop for Expr {
+    fn drop(&mut self) {
+        fn take_children(expr: &mut Expr, stack: &mut Vec<Expr>) {
+            use std::mem::replace;
+            match expr {
+                Expr::Unary(_, _, x) => stack.push(replace(x, Expr::Num(0.0, 0..0))),
+                Expr::Binary(_, _, x, y) => {
+                    stack.push(replace(x, Expr::Num(0.0, 0..0)));
+                    stack.push(replace(y, Expr::Num(0.0, 0..0)));
+                }
+                Expr::Call(_, _, args) => stack.append(args),
+                Expr::Var(..) | Expr::Num(..) => {}
+            }
+        }
+
+        let mut stack = Vec::new();
+        take_children(self, &mut stack);
+        while let Some(mut expr) = stack.pop() {
+            take_children(&mut expr, &mut stack);
+        }
+    }
+}
+
+impl Statement {
+    pub(crate) fn is_comparison(&self) -> bool {
+        match self {
+            Statement::Expr(expr) | Statement::Assign(_, expr) => expr.is_comparison(),
+        }
+    }
+
+    pub(crate) fn exec(self, env: &mut Context) -> Result<f64, CalcErr> {
+        match self {
+            Statement::Expr(expr) => expr.eval(env),
+            Statement::Assign(name, expr) => {
+                let val = expr.eval(env)?;
+                env.set(&name, val);
+                Ok(val)
+            }
+        }
+    }
+}
+
+mod recursive_descent_parse {
+    use super::*;
+    use Expr::*;
+
+    struct Parser<'a> {
+        tokens: Peekable<lex::Lexer<'a>>,
+        options: &'a Options,
+        env: &'a Context,
+        depth: usize,
+        // whether a `|` after an operand closes `|x|` rather than being an or
+        in_bars: bool,
+        // the number of chars in the input
+        end: lex::TokenPosition,
+        // what the context's resolver gave for each name it was asked about
+        resolved: HashMap<String, Option<f64>>,
+    }
+
+    impl<'a> Parser<'a> {
+        fn ran_out(&self, msg: &'static str) -> CalcErr {
+            CalcErr::Incomplete {
+                span: self.end..self.end + 1,
+                msg,
+            }
+        }
+
+        fn constant(&self, name: &str) -> Option<f64> {
+            match constant(name) {
+                None if self.options.physics => physical_constant(name),
+                x => x,
+            }
+        }
+
+        fn resolve(&mut self, name: &str) -> Option<f64> {
+            let resolver = self.env.resolver.as_ref()?;
+            *self
+                .resolved
+                .entry(name.to_string())
+                .or_insert_with(|| resolver(name))
+        }
+
+        // parses something nested one level deeper than the current position,
+        // inside the token at `span`
+        fn nested(
+            &mut self,
+            span: &lex::Span,
+            parse: impl FnOnce(&mut Self) -> ExprResult,
+        ) -> ExprResult {
+            if self.depth >= self.options.max_depth {
+                return Err(CalcErr::TooDeep { span: span.clone() });
+            }
+            self.depth += 1;
+            let expr = parse(self);
+            self.depth -= 1;
+            expr
+        }
+
+        // brackets and arguments start afresh, so `|(a | b)|` is an or in bars
+        fn bars(
+            &mut self,
+            in_bars: bool,
+            parse: impl FnOnce(&mut Self) -> ExprResult,
+        ) -> ExprResult {
+            let outer = std::mem::replace(&mut self.in_bars, in_bars);
+            let expr = parse(self);
+            self.in_bars = outer;
+            expr
+        }
+
+        fn next(&mut self) -> Option<lex::LexResult> {
+            self.tokens.next()
+        }
+
+        fn peek(&mut self) -> Option<&lex::LexResult> {
+            self.tokens.peek()
+        }
+    }
+
+    fn parse_complete_statement(input: &mut Parser) -> Result<Statement, CalcErr> {
+        let mut lookahead = input.tokens.clone();
+        if let (Some(Ok((span, Ident(name)))), Some(Ok((_, Equals)))) =
+            (lookahead.next(), lookahead.next())
+        {
+            if input.constant(&name).is_some()
+                || function(&name).is_some()
+                || is_ans(&name)
+                || name == MOD
+                || name == XOR
+            {
+                return Err(CalcErr::Lex(LexErr::spanning(span, RESERVED_NAME)));
+            }
+            if input.options.reassign == Reassign::Error && input.env.contains(&name) {
+                return Err(CalcErr::Lex(LexErr::spanning(span, ALREADY_ASSIGNED)));
+            }
+            input.tokens = lookahead;
+            return Ok(Statement::Assign(name, parse_complete_expr(input)?));
+        }
+        Ok(Statement::Expr(parse_complete_expr(input)?))
+    }
+
+    fn parse_complete_expr(input: &mut Parser) -> ExprResult {
+        let expr = parse_bit_or(input)?;
+        match input.next() {
+            None => Ok(expr),
+            Some(x) => {
+                let (span, _) = x?;
+                Err(CalcErr::Lex(LexErr::spanning(span, EXPECTED_END)))
+            }
+        }
+    }
+
+    // the bitwise operators bind looser than comparisons, as in C, from `|` up
+    // through `xor` and `&` to the shifts
+    fn parse_bit_or(input: &mut Parser) -> ExprResult {
+        let mut expr = parse_bit_xor(input)?;
+        loop {
+            let in_bars = input.in_bars;
+            let span = match input.peek() {
+                Some(Ok((span, Bar))) if !in_bars => span.clone(),
+                _ => return Ok(expr),
+            };
+            input.next();
+            expr = Binary(BitOr, span, Box::new(expr), Box::new(parse_bit_xor(input)?))
+        }
+    }
+
+    fn parse_bit_xor(input: &mut Parser) -> ExprResult {
+        let mut expr = parse_bit_and(input)?;
+        loop {
+            let span = match input.peek() {
+                Some(Ok((span, Ident(name)))) if name == XOR => span.clone(),
+                _ => return Ok(expr),
+            };
+            input.next();
+            expr = Binary(
+                BitXor,
+                span,
+                Box::new(expr),
+                Box::new(parse_bit_and(input)?),
+            )
+        }
+    }
+
+    fn parse_bit_and(input: &mut Parser) -> ExprResult {
+        let mut expr = parse_shift(input)?;
+        loop {
+            let span = match input.peek() {
+                Some(Ok((span, Amp))) => span.clone(),
+                _ => return Ok(expr),
+            };
+            input.next();
+            expr = Binary(BitAnd, span, Box::new(expr), Box::new(parse_shift(input)?))
+        }
+    }
+
+    fn parse_shift(input: &mut Parser) -> ExprResult {
+        let mut expr = parse_comparison(input)?;
+        loop {
+            let (span, op) = match input.peek() {
+                Some(Ok((span, Shl))) => (span.clone(), ShiftLeft),
+                Some(Ok((span, Shr))) => (span.clone(), ShiftRight),
+                _ => return Ok(expr),
+            };
+            input.next();
+            expr = Binary(op, span, Box::new(expr), Box::new(parse_comparison(input)?))
+        }
+    }
+
+    // comparisons bind looser than every arithmetic operator and give 1 or 0
+    fn parse_comparison(input: &mut Parser) -> ExprResult {
+        let mut expr = parse_expr(input)?;
+        loop {
+            let (span, op) = match input.peek() {
+                Some(Ok((span, Lt))) => (span.clone(), Less),
+                Some(Ok((span, Le))) => (span.clone(), LessEq),
+                Some(Ok((span, Gt))) => (span.clone(), Greater),
+                Some(Ok((span, Ge))) => (span.clone(), GreaterEq),
+                Some(Ok((span, EqEq))) => (span.clone(), Equal),
+                Some(Ok((span, Ne))) => (span.clone(), NotEqual),
+                _ => return Ok(expr),
+            };
+            input.next();
+            expr = Binary(op, span, Box::new(expr), Box::new(parse_expr(input)?))
+        }
+    }
+
+    fn parse_expr(input: &mut Parser) -> ExprResult {
+        let mut expr = parse_term(input)?;
+        loop {
+            match input.peek() {
+                None => return Ok(expr),
+                Some(x) => match x {
+                    Ok((span, Plus)) => {
+                        let span = span.clone();
+                        input.next();
+                        expr = Binary(Add, span, Box::new(expr), Box::new(parse_term(input)?))
+                    }
+                    Ok((span, Dash)) => {
+                        let span = span.clone();
+                        input.next();
+                        expr = Binary(Sub, span, Box::new(expr), Box::new(parse_term(input)?))
+                    }
+                    _ => return Ok(expr),
+                },
+            }
+        }
+    }
+
+    fn parse_term(input: &mut Parser) -> ExprResult {
+        let mut expr = parse_implicit(input)?;
+        loop {
+            let (span, op) = match input.peek() {
+                Some(Ok((span, Star))) => (span.clone(), Mul),
+                Some(Ok((span, Slash))) => (span.clone(), Div),
+                Some(Ok((span, DoubleSlash))) => (span.clone(), IntDiv),
+                Some(Ok((span, Percent))) => (span.clone(), Mod),
+                Some(Ok((span, Ident(name)))) if name == MOD => (span.clone(), EuclidMod),
+                _ => return Ok(expr),
+            };
+            input.next();
+            expr = Binary(op, span, Box::new(expr), Box::new(parse_implicit(input)?))
+        }
+    }
+
+    // a factor directly followed by `(` or a name multiplies it, binding tighter
+    // than `*` and `/` so that 1/2x is 1/(2x); a following number is not implied,
+    // so that `2 2` stays an error, and neither are the `mod` and `xor` operators
+    fn parse_implicit(input: &mut Parser) -> ExprResult {
+        let mut expr = parse_factor(input)?;
+        loop {
+            let span = match input.peek() {
+                Some(Ok((span, Ident(name)))) if name != MOD && name != XOR => span.clone(),
+                Some(Ok((span, open))) if closing_bracket(open).is_some() => span.clone(),
+                _ => return Ok(expr),
+            };
+            expr = Binary(Mul, span, Box::new(expr), Box::new(parse_factor(input)?))
+        }
+    }
+
+    fn parse_factor(input: &mut Parser) -> ExprResult {
+        let mut expr = parse_postfix(input)?;
+        loop {
+            match input.peek() {
+                None => return Ok(expr),
+                Some(Ok((span, Caret))) => {
+                    let span = span.clone();
+                    input.next();
+                    let exponent = input.nested(&span, parse_factor)?;
+                    expr = Binary(Pow, span, Box::new(expr), Box::new(exponent))
+                }
+                _ => return Ok(expr),
+            }
+        }
+    }
+
+    fn parse_postfix(input: &mut Parser) -> ExprResult {
+        let mut expr = parse_primary(input)?;
+        while let Some(Ok((span, Bang))) = input.peek() {
+            let span = span.clone();
+            input.next();
+            expr = Unary(Fact, span, Box::new(expr));
+        }
+        Ok(expr)
+    }
+
+    fn parse_primary(input: &mut Parser) -> ExprResult {
+        match input.next() {
+            None => Err(input.ran_out(INCOMPLETE_EXPR)),
+            Some(x) => match x? {
+                (span, Number(n)) if !n.is_finite() => Err(CalcErr::Math {
+                    span,
+                    msg: NOT_FINITE,
+                }),
+                (span, Number(n)) => Ok(Num(n, span)),
+                (span, open) if closing_bracket(&open).is_some() => {
+                    let close = closing_bracket(&open).expect("checked by the guard");
+                    input.nested(&span.clone(), |input| {
+                        parse_parenthesised(input, span, close)
+                    })
+                }
+                (span, Bar) => input.nested(&span.clone(), |input| parse_abs(input, span)),
+                (span, Dash) => {
+                    let x = input.nested(&span, parse_signed)?;
+                    Ok(Unary(Neg, span, Box::new(x)))
+                }
+                // unary plus binds like minus but leaves its operand as it is
+                (span, Plus) => input.nested(&span, parse_signed),
+                (span, Ident(name)) if name == MOD || name == XOR => {
+                    Err(CalcErr::Lex(LexErr::spanning(span, EXPECTED_OPERAND)))
+                }
+                (span, Ident(name)) if is_ans(&name) => {
+                    if input.env.contains(ANS) {
+                        Ok(Var(ANS.to_string(), span))
+                    } else {
+                        Err(CalcErr::Lex(LexErr::spanning(span, NO_PREVIOUS_RESULT)))
+                    }
+                }
+                (span, Ident(name)) => match input.constant(&name) {
+                    Some(x) => Ok(Num(x, span)),
+                    None if input.env.contains(&name) => Ok(Var(name, span)),
+                    None => {
+                        // the resolver is only asked about names that aren't functions
+                        let resolved = match function(&name) {
+                            Some(_) => None,
+                            None => input.resolve(&name),
+                        };
+                        match resolved {
+                            Some(x) if !x.is_finite() => Err(CalcErr::Math {
+                                span,
+                                msg: NOT_FINITE,
+                            }),
+                            Some(x) => Ok(Num(x, span)),
+                            None => {
+                                input.nested(&span.clone(), |input| parse_call(input, span, name))
+                            }
+                        }
+                    }
+                },
+                (span, _) => Err(CalcErr::Lex(LexErr::spanning(span, EXPECTED_OPERAND))),
+            },
+        }
+    }
+
+    fn parse_signed(input: &mut Parser) -> ExprResult {
+        match input.options.neg_binding {
+            NegBinding::Loose => parse_factor(input),
+            NegBinding::Tight => parse_primary(input),
+        }
+    }
+
+    fn parse_call(input: &mut Parser, span: lex::Span, name: String) -> ExprResult {
+        if function(&name).is_none() {
+            return match input.peek() {
+                Some(Ok((_, LParen))) => {
+                    Err(CalcErr::Lex(LexErr::spanning(span, UNKNOWN_FUNCTION)))
+                }
+                _ => Err(CalcErr::Lex(LexErr::spanning(span, UNKNOWN_IDENTIFIER))),
+            };
+        }
+        match input.next() {
+            None => Err(input.ran_out(EXPECTED_LPAREN)),
+            Some(x) => match x? {
+                (paren, LParen) => {
+                    let args = parse_args(input, paren)?;
+                    match function(&name).expect("checked above") {
+                        (f, _) if f.accepts(args.len()) => {
+                            Ok(in_angle_unit(input.options.angle, name, span, args))
+                        }
+                        (_, usage) => Err(CalcErr::Lex(LexErr::spanning(span, usage))),
+                    }
+                }
+                (span, _) => Err(CalcErr::Lex(LexErr::spanning(span, EXPECTED_LPAREN))),
+            },
+        }
+    }
+
+    fn parse_args(input: &mut Parser, open: lex::Span) -> Result<Vec<Expr>, CalcErr> {
+        let mut args = vec![input.bars(false, parse_bit_or)?];
+        loop {
+            match input.next() {
+                None => {
+                    return Err(CalcErr::Incomplete {
+                        span: open,
+                        msg: UNCLOSED_PAREN,
+                    })
+                }
+                Some(x) => match x? {
+                    (_, Comma) => args.push(input.bars(false, parse_bit_or)?),
+                    (_, RParen) => return Ok(args),
+                    (span, _) => {
+                        return Err(CalcErr::Lex(LexErr::spanning(span, EXPECTED_ARG_END)))
+                    }
+                },
+            }
+        }
+    }
+
+    fn parse_parenthesised(input: &mut Parser, open: lex::Span, close: lex::Token) -> ExprResult {
+        let expr = input.bars(false, parse_bit_or)?;
+        match input.next() {
+            None => Err(CalcErr::Incomplete {
+                span: open,
+                msg: unclosed(&close),
+            }),
+            Some(x) => match x? {
+                (_, token) if token == close => Ok(expr),
+                (span, RParen | RBracket | RBrace) => {
+                    Err(CalcErr::Lex(LexErr::spanning(span, MISMATCHED_BRACKET)))
+                }
+                (span, _) => Err(CalcErr::Lex(LexErr::spanning(
+                    span,
+                    expected_closing(&close),
+                ))),
+            },
+        }
+    }
+
+    // `|x|`, where a bar after an operand closes the innermost open one, so
+    // `|3 - |-1||` is 2; bars don't imply multiplication like brackets do
+    fn parse_abs(input: &mut Parser, open: lex::Span) -> ExprResult {
+        let expr = input.bars(true, parse_bit_or)?;
+        match input.next() {
+            None => Err(CalcErr::Incomplete {
+                span: open,
+                msg: UNCLOSED_BAR,
+            }),
+            Some(x) => match x? {
+                (_, Bar) => Ok(Unary(Abs, open, Box::new(expr))),
+                (span, _) => Err(CalcErr::Lex(LexErr::spanning(span, EXPECTED_BAR))),
+            },
+        }
+    }
+
+    fn parser<'a>(input: &'a str, options: &'a Options, env: &'a Context) -> Parser<'a> {
+        Parser {
+            tokens: lex::Lexer::new(input)
+                .allow_primes(options.primes)
+                .peekable(),
+            options,
+            env,
+            depth: 0,
+            in_bars: false,
+            end: input.chars().count(),
+            resolved: HashMap::new(),
+        }
+    }
+
+    pub(super) fn parse(input: &str, options: &Options, env: &Context) -> ExprResult {
+        parse_complete_expr(&mut parser(input, options, env))
+    }
+
+    pub(super) fn parse_statement(
+        input: &str,
+        options: &Options,
+        env: &Context,
+    ) -> Result<Statement, CalcErr> {
+        parse_complete_statement(&mut parser(input, options, env))
+    }
+}
+
+pub fn parse(input: &str) -> Result<Expr, CalcErr> {
+    parse_with(input, &Options::default(), &Context::new())
+}
+
+/// Parses an expression that can use the variables in `ctx`, so that it can
+/// be evaluated later with other values for them. Evaluating it with a
+/// context that is missing one of them is an error at the variable.
+pub fn parse_with_vars(input: &str, ctx: &Context) -> Result<Expr, CalcErr> {
+    parse_with(input, &Options::default(), ctx)
+}
+
+pub(crate) fn parse_with(input: &str, options: &Options, env: &Context) -> ExprResult {
+    recursive_descent_parse::parse(input, options, env)
+}
+
+pub(crate) fn parse_statement(
+    input: &str,
+    options: &Options,
+    env: &Context,
+) -> Result<Statement, CalcErr> {
+    recursive_descent_parse::parse_statement(input, options, env)
+}
+
+/// Evaluates an expression using the variables in `ctx`, where any other name
+/// is an unknown identifier.
+pub fn eval_with(input: &str, ctx: &Context) -> Result<f64, CalcErr> {
+    parse_with_vars(input, ctx)?.eval(ctx)
+}
+
+/// Like `eval_with`, but `input` can also be an assignment like `x = 2`, which
+/// sets the variable in `ctx`.
+pub fn exec_with(input: &str, ctx: &mut Context) -> Result<f64, CalcErr> {
+    parse_statement(input, &Options::default(), ctx)?.exec(ctx)
+}
+
+pub fn eval(input: &str) -> Result<f64, CalcErr> {
+    eval_with(input, &Context::new())
+}
+
+// the innermost balanced `( .. )`, or `[ .. ]` or `{ .. }`, around the char at
+// `offset`, as char positions of the first and last chars, where the
+// parentheses of a call start at the function's name so that the whole call
+// is chosen rather than its arguments
+fn enclosing_parens(input: &str, offset: usize) -> Result<Option<(usize, usize)>, CalcErr> {
+    let mut open = Vec::new();
+    let mut innermost = None;
+    let mut name = None;
+    for (span, token) in lex::tokenize(input)? {
+        let pos = span.start;
+        let called = name.take();
+        match token {
+            LParen => open.push(called.unwrap_or(pos)),
+            LBracket | LBrace => open.push(pos),
+            Ident(ref f) if function(f).is_some() => name = Some(pos),
+            RParen | RBracket | RBrace => match open.pop() {
+                // inner pairs close first, so the first match is the innermost
+                Some(start) if innermost.is_none() && start <= offset && offset <= pos => {
+                    innermost = Some((start, pos))
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+    Ok(innermost)
+}
+
+/// Evaluates only the innermost parenthesised subexpression or call containing
+/// the byte at `byte_offset`, or the whole input if there is no such
+/// subexpression, with the variables in `ctx`.
+pub fn eval_subexpr(
+    input: &str,
+    byte_offset: usize,
+    options: &Options,
+    ctx: &Context,
+) -> Result<f64, CalcErr> {
+    let offset = input
+        .char_indices()
+        .take_while(|(i, _)| *i < byte_offset)
+        .count()
+        .min(input.chars().count().saturating_sub(1));
+    let (start, end) = match enclosing_parens(input, offset)? {
+        Some(span) => span,
+        None => return parse_with(input, options, ctx)?.eval(ctx),
+    };
+    let bytes: Vec<usize> = input.char_indices().map(|(i, _)| i).collect();
+    let subexpr = &input[bytes[start]..=bytes[end]];
+    parse_with(subexpr, options, ctx)
+        .and_then(|expr| expr.eval(ctx))
+        .map_err(|e| at_offset(e, start))
+}
+
+fn shift(span: lex::Span, offset: usize) -> lex::Span {
+    span.start + offset..span.end + offset
+}
+
+// moves the position of an error in a part of the input that starts at char
+// `offset` to its position in the whole input
+pub(crate) fn at_offset(e: CalcErr, offset: usize) -> CalcErr {
+    match e {
+        CalcErr::Lex(e) => CalcErr::Lex(LexErr::spanning(shift(e.span, offset), e.message)),
+        CalcErr::Incomplete { span, msg } => CalcErr::Incomplete {
+            span: shift(span, offset),
+            msg,
+        },
+        CalcErr::Math { span, msg } => CalcErr::Math {
+            span: shift(span, offset),
+            msg,
+        },
+        e => e,
+    }
+}
+
+/// Splits `input` into the statements separated by `;`, each paired with the
+/// char position it starts at. A trailing `;` is ignored, but an empty
+/// statement anywhere else is an error.
+pub fn split_statements(input: &str) -> Result<Vec<(usize, &str)>, CalcErr> {
+    let semicolons = lex::tokenize(input)?
+        .into_iter()
+        .filter(|(_, token)| *token == Semicolon)
+        .map(|(span, _)| span.start);
+    let mut bytes: Vec<usize> = input.char_indices().map(|(i, _)| i).collect();
+    bytes.push(input.len());
+
+    let mut statements = Vec::new();
+    let mut start = 0;
+    for end in semicolons {
+        let statement = &input[bytes[start]..bytes[end]];
+        if statement.trim().is_empty() {
+            return Err(CalcErr::Lex(LexErr::new(end, EMPTY_STATEMENT)));
+        }
+        statements.push((start, statement));
+        start = end + 1;
+    }
+    let rest = &input[bytes[start]..];
+    if statements.is_empty() || !rest.trim().is_empty() {
+        statements.push((start, rest));
+    }
+    Ok(statements)
+}
+
+// an error in the statement starting at `offset`, where running out of input
+// before the `;` that ends the statement points at that `;` with what was
+// expected in its place, unless a bracket before it was never closed
+pub(crate) fn statement_err(e: CalcErr, input: &str, offset: usize, statement: &str) -> CalcErr {
+    let len = statement.chars().count();
+    let end = offset + len;
+    match e {
+        CalcErr::Incomplete { span, msg } if end < input.chars().count() => {
+            if span.start < len {
+                CalcErr::Lex(LexErr::spanning(shift(span, offset), msg))
+            } else {
+                let msg = if msg == INCOMPLETE_EXPR {
+                    EXPECTED_OPERAND
+                } else {
+                    msg
+                };
+                CalcErr::Lex(LexErr::new(end, msg))
+            }
+        }
+        e => at_offset(e, offset),
+    }
+}
+
+// moves the positions in a tree parsed from part of an input to where they
+// are in the whole of it
+fn shift_positions(expr: &mut Expr, offset: usize) {
+    let mut stack = vec![expr];
+    while let Some(expr) = stack.pop() {
+        match expr {
+            Expr::Unary(_, span, x) => {
+                *span = shift(span.clone(), offset);
+                stack.push(x);
+            }
+            Expr::Binary(_, span, x, y) => {
+                *span = shift(span.clone(), offset);
+                stack.push(x);
+                stack.push(y);
+            }
+            Expr::Call(_, span, args) => {
+                *span = shift(span.clone(), offset);
+                stack.extend(args.iter_mut());
+            }
+            Expr::Var(_, span) | Expr::Num(_, span) => *span = shift(span.clone(), offset),
+        }
+    }
+}
+
+/// Parses each of the `;` separated expressions in `input`, with positions
+/// counted from the start of `input`.
+pub fn parse_all(input: &str) -> Result<Vec<Expr>, CalcErr> {
+    split_statements(input)?
+        .into_iter()
+        .map(|(offset, statement)| {
+            let mut expr =
+                parse(statement).map_err(|e| statement_err(e, input, offset, statement))?;
+            shift_positions(&mut expr, offset);
+            Ok(expr)
+        })
+        .collect()
+}
+
+/// Parses `input` once and evaluates it with the variable `var` bound to each
+/// of `values` in turn, as for plotting it. Any other variable is unknown.
+pub fn eval_over(input: &str, var: &str, values: &[f64]) -> Result<Vec<f64>, CalcErr> {
+    eval_each(input, var, values)?.into_iter().collect()
+}
+
+/// Like `eval_over`, but with the result for each value, so that values the
+/// expression is undefined at don't stop it being evaluated at the others.
+/// Only an input that can't be parsed is an error for every value.
+pub fn eval_each(
+    input: &str,
+    var: &str,
+    values: &[f64],
+) -> Result<Vec<Result<f64, CalcErr>>, CalcErr> {
+    let mut env = Context::new();
+    env.set(var, 0.0);
+    // only the parts that depend on the variable are left to do for each value
+    let expr = parse_with(input, &Options::default(), &env)?.simplify();
+    Ok(values
+        .iter()
+        .map(|&x| {
+            env.set(var, x);
+            expr.eval(&env)
+        })
+        .collect())
+}
+
+/// Evaluates each of the `;` separated statements in `input` in turn, so that
+/// later statements can use variables assigned by earlier ones.
+pub fn eval_all(input: &str) -> Result<Vec<f64>, CalcErr> {
+    let mut env = Context::new();
+    split_statements(input)?
+        .into_iter()
+        .map(|(offset, statement)| {
+            exec_with(statement, &mut env).map_err(|e| statement_err(e, input, offset, statement))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::rng::Rng;
+
+    fn ran_out_at<T>(pos: lex::TokenPosition, msg: &'static str) -> Result<T, CalcErr> {
+        Err(CalcErr::Incomplete {
+            span: pos..pos + 1,
+            msg,
+        })
+    }
+
+    #[test]
+    pub fn num_is_parsed() {
+        assert_eq!(1.0, eval("1.0").unwrap());
+    }
+
+    #[test]
+    pub fn mul() {
+        assert_eq!(15.0, eval("3 * 5").unwrap());
+    }
+
+    #[test]
+    pub fn modulus() {
+        assert_eq!(1.0, eval("1 % 2").unwrap());
+        assert_eq!(0.0, eval("4 % 2").unwrap());
+        assert_eq!(2.0, eval("8 % 3").unwrap());
+        assert_eq!(4.0, eval("11 % 7").unwrap());
+        assert_eq!(2.0, eval("8 % 3").unwrap());
+    }
+
+    #[test]
+    pub fn remainder_and_euclidean_mod_signs() {
+        assert_eq!(Ok(1.0), eval("7 % 3"));
+        assert_eq!(Ok(-1.0), eval("-7 % 3"));
+        assert_eq!(Ok(1.0), eval("7 % -3"));
+        assert_eq!(Ok(-1.0), eval("-7 % -3"));
+
+        assert_eq!(Ok(1.0), eval("7 mod 3"));
+        assert_eq!(Ok(2.0), eval("-7 mod 3"));
+        assert_eq!(Ok(1.0), eval("7 mod -3"));
+        assert_eq!(Ok(2.0), eval("-7 mod -3"));
+    }
+
+    #[test]
+    pub fn mod_is_an_operator_not_a_name() {
+        assert_eq!(Ok(12.0), eval("2 * 17 mod 7 * 2"));
+        assert_eq!(Ok(3.0), eval("2(5) mod 7"));
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 2..5,
+                msg: DIVISION_BY_ZERO
+            }),
+            eval("5 mod 0")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::spanning(0..3, EXPECTED_OPERAND))),
+            eval("mod 3")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::spanning(0..3, RESERVED_NAME))),
+            exec_with("mod = 3", &mut Context::new())
+        );
+    }
+
+    #[test]
+    pub fn add() {
+        assert_eq!(9.0, eval("2 + 7").unwrap());
+    }
+
+    #[test]
+    pub fn sub() {
+        assert_eq!(10.0, eval("11 - 1").unwrap());
+    }
+
+    #[test]
+    pub fn pow() {
+        assert_eq!(25.0, eval("5^2").unwrap());
+        assert_eq!(3.0, eval("9^0.5").unwrap());
+    }
+
+    #[test]
+    pub fn double_neg() {
+        assert_eq!(2.0, eval("1--1").unwrap());
+    }
+
+    #[test]
+    pub fn chained_add() {
+        assert_eq!(3.0, eval("1+1+1").unwrap());
+    }
+
+    #[test]
+    pub fn paren_before_add() {
+        assert_eq!(0.3125, eval("(1+0.25)*0.25").unwrap());
+        assert_eq!(0.66, eval("1.2*(0.3+0.25)").unwrap());
+    }
+
+    #[test]
+    pub fn paren_before_mul() {
+        assert_eq!(12.0, eval("2 * (5 + 1)").unwrap());
+        assert_eq!(11.0, eval("(2 * 5) + 1").unwrap());
+    }
+
+    #[test]
+    pub fn mul_before_add() {
+        assert_eq!(14.0, eval("4 * 3 + 2").unwrap());
+        assert_eq!(14.0, eval("2 + 4 * 3").unwrap());
+    }
+
+    #[test]
+    pub fn neg_before_add() {
+        assert_eq!(0.0, eval("-5 + 5").unwrap());
+        assert_eq!(-10.0, eval("-(5 + 5)").unwrap());
+    }
+
+    #[test]
+    pub fn pow_before_all() {
+        assert_eq!(-25.0, eval("-5^2").unwrap());
+        assert_eq!(37.0, eval("6^2+1").unwrap());
+        assert_eq!(200.0, eval("2*10^2").unwrap());
+        assert_eq!(2.0, eval("2^2/2").unwrap());
+    }
+
+    #[test]
+    pub fn double_star_is_pow() {
+        assert_eq!(-25.0, eval("-5**2").unwrap());
+        assert_eq!(37.0, eval("6**2+1").unwrap());
+        assert_eq!(200.0, eval("2*10**2").unwrap());
+        assert_eq!(2.0, eval("2**2/2").unwrap());
+        assert_eq!(1024.0, eval("2**10").unwrap());
+        assert_eq!(0.125, eval("2**-3").unwrap());
+        assert_eq!(-4.0, eval("-2**2").unwrap());
+        assert_eq!(eval("2^3^2"), eval("2**3**2"));
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(4, EXPECTED_OPERAND))),
+            eval("2 * *3")
+        );
+    }
+    #[test]
+    pub fn unary_plus() {
+        assert_eq!(5.0, eval("+5").unwrap());
+        assert_eq!(6.0, eval("3 * +2").unwrap());
+        assert_eq!(-5.0, eval("+-+5").unwrap());
+        assert_eq!(-5.0, eval("-+5").unwrap());
+        assert_eq!(8.0, eval("5 + + 3").unwrap());
+        assert_eq!(5.0, eval("2++3").unwrap());
+        assert_eq!(ran_out_at(5, INCOMPLETE_EXPR), eval("5 + +"));
+        assert_eq!(parse(" 2^2"), parse("+2^2"));
+    }
+
+    #[test]
+    pub fn neg_binding() {
+        let eval_tight = |input| {
+            let options = Options {
+                neg_binding: NegBinding::Tight,
+                ..Options::default()
+            };
+            parse_with(input, &options, &Context::new()).and_then(|e| e.eval(&Context::new()))
+        };
+        assert_eq!(-25.0, eval("-5^2").unwrap());
+        assert_eq!(25.0, eval_tight("-5^2").unwrap());
+        assert_eq!(-8.0, eval_tight("-2^3").unwrap());
+        assert_eq!(-10.0, eval_tight("-5*2").unwrap());
+        assert_eq!(0.125, eval_tight("2^-3").unwrap());
+        assert_eq!(-4.0, eval_tight("-(2^2)").unwrap());
+        assert_eq!(4.0, eval_tight("--2^2").unwrap());
+    }
+
+    #[test]
+    pub fn factorial() {
+        assert_eq!(1.0, eval("0!").unwrap());
+        assert_eq!(120.0, eval("5!").unwrap());
+        assert_eq!(3628800.0, eval("10!").unwrap());
+        assert_eq!(120.0, eval("(3+2)!").unwrap());
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 3..4,
+                msg: NOT_FINITE
+            }),
+            eval("171!")
+        );
+    }
+
+    #[test]
+    pub fn factorial_of_non_integer_or_negative_is_an_error() {
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 3..4,
+                msg: FACTORIAL_DOMAIN
+            }),
+            eval("2.5!")
+        );
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 4..5,
+                msg: FACTORIAL_DOMAIN
+            }),
+            eval("(-1)!")
+        );
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 5..6,
+                msg: FACTORIAL_DOMAIN
+            }),
+            eval("(1/2)!")
+        );
+        assert_eq!(Ok(-6.0), eval("-3!"));
+    }
+
+    #[test]
+    pub fn repeated_bangs_apply_factorial_repeatedly() {
+        // not the double factorial 5!! = 5 * 3 * 1
+        assert_eq!(720.0, eval("3!!").unwrap());
+        assert_eq!(eval("(5!)!"), eval("5!!"));
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 2..3,
+                msg: NOT_FINITE
+            }),
+            eval("6!!")
+        );
+    }
+
+    #[test]
+    pub fn factorial_binds_tighter_than_pow_and_neg() {
+        assert_eq!(36.0, eval("3!^2").unwrap());
+        assert_eq!(64.0, eval("2^3!").unwrap());
+        assert_eq!(-6.0, eval("-3!").unwrap());
+        assert_eq!(12.0, eval("2*3!").unwrap());
+    }
+
+    #[test]
+    pub fn parse_tree_reflects_precedence() {
+        match &parse("1 + 2 * 3").unwrap() {
+            Expr::Binary(Add, span, x, y) if *span == (2..3) => match (&**x, &**y) {
+                (Expr::Num(x, span), Expr::Binary(Mul, times, y, z)) if *times == (6..7) => {
+                    assert_eq!((1.0, 0..1), (*x, span.clone()));
+                    assert_eq!(Expr::Num(2.0, 4..5), **y);
+                    assert_eq!(Expr::Num(3.0, 8..9), **z);
+                }
+                e => panic!("unexpected operands {:?}", e),
+            },
+            e => panic!("unexpected tree {:?}", e),
+        }
+    }
+
+    #[test]
+    pub fn bars_give_the_absolute_value() {
+        assert_eq!(Ok(4.0), eval("|3-7|"));
+        assert_eq!(Ok(3.0), eval("|{-3}|"));
+        assert_eq!(Ok(5.0), eval("2 + |-3|"));
+        // the innermost bar is closed first
+        assert_eq!(Ok(2.0), eval("|3 - |−1||"));
+        assert_eq!(Ok(1.0), eval("||1 - 2| - ||3||| - 1"));
+        assert_eq!(ran_out_at(0, UNCLOSED_BAR), eval("|1 + 2"));
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(3, EXPECTED_BAR))),
+            eval("(|1)|")
+        );
+    }
+
+    #[test]
+    pub fn brackets_and_braces_group() {
+        assert_eq!(Ok(12.0), eval("2 * [3 + {4 - 1}]"));
+        assert_eq!(Ok(9.0), eval("{1 + 2}[1 + 2]"));
+        assert_eq!(Ok(2.0), eval("sqrt([4])"));
+        assert_eq!(ran_out_at(0, UNCLOSED_BRACKET), eval("[1 + 2"));
+    }
+
+    #[test]
+    pub fn mismatched_brackets_are_rejected() {
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(6, MISMATCHED_BRACKET))),
+            eval("(1 + 2]")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(7, MISMATCHED_BRACKET))),
+            eval("[2 * {3)}")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(4, EXPECTED_LPAREN))),
+            eval("sqrt[4]")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(0, EXPECTED_OPERAND))),
+            eval("]")
+        );
+    }
+
+    #[test]
+    pub fn is_left_associative() {
+        assert_eq!(1.0, eval("5 * 2 % 3").unwrap());
+        assert_eq!(9.0, eval("6 / 2 * 3").unwrap())
+    }
+
+    #[test]
+    pub fn unexpected_token_is_rejected() {
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(7, EXPECTED_OPERAND))),
+            eval("1 - 5 */ 5")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(2, EXPECTED_OPERAND))),
+            eval("2()")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(3, EXPECTED_OPERAND))),
+            eval("2*()")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(0, EXPECTED_OPERAND))),
+            eval("%5")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(3, EXPECTED_OPERAND))),
+            eval("2 %% 3")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(0, EXPECTED_OPERAND))),
+            eval(")")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(2, EXPECTED_END))),
+            eval("1 2)")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(7, EXPECTED_RPAREN))),
+            eval("(1 + 2 3)")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(3, EXPECTED_RBRACKET))),
+            eval("[1 , 2]")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(10, EXPECTED_ARG_END))),
+            eval("lerp(1, 2 3)")
+        );
+    }
+
+    #[test]
+    pub fn incomplete_expr_is_identified() {
+        assert_eq!(ran_out_at(4, INCOMPLETE_EXPR), eval("2 * "));
+        assert_eq!(ran_out_at(5, INCOMPLETE_EXPR), eval("2 * ("));
+        assert_eq!(ran_out_at(4, UNCLOSED_PAREN), eval("2 * (5+2"));
+        assert_eq!(Ok(14.0), eval("2 * (5+2)"));
+    }
+
+    #[test]
+    pub fn unknown_symbol_is_rejected() {
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(4, lex::UNKNOWN_SYMBOL))),
+            eval("2 * $")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(6, lex::UNKNOWN_SYMBOL))),
+            eval("2 * (1$")
+        );
+    }
 
-const UNEXPECTED_TOKEN: &str = "not expected here";
+    #[test]
+    pub fn functions() {
+        assert_eq!(3.0, eval("sqrt(9)").unwrap());
+        assert_eq!(0.0, eval("ln(1)").unwrap());
+        assert_eq!(0.0, eval("sin(0)").unwrap());
+        assert_eq!(5.0, eval("1 + abs(-2) * 2").unwrap());
+        assert_eq!(3.0, eval("cbrt(27)").unwrap());
+        assert_eq!(2.0, eval("log(100)").unwrap());
+        assert_eq!(0.0, eval("atan(0)").unwrap());
+    }
 
-#[derive(Debug, PartialEq, Eq)]
-pub enum CalcErr {
-    Lex(lex::LexErr),
-    Incomplete,
-}
+    #[test]
+    pub fn ilog_is_the_floor_of_the_logarithm() {
+        assert_eq!(Ok(3.0), eval("ilog(1000, 10)"));
+        assert_eq!(Ok(3.0), eval("ilog(8, 2)"));
+        assert_eq!(Ok(2.0), eval("ilog(999, 10)"));
+        assert_eq!(Ok(0.0), eval("ilog(1, 7)"));
+        assert_eq!(Ok(15.0), eval("ilog(10^15, 10)"));
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 0..4,
+                msg: ILOG_DOMAIN
+            }),
+            eval("ilog(8, 1)")
+        );
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 0..4,
+                msg: ILOG_DOMAIN
+            }),
+            eval("ilog(0.5, 2)")
+        );
+    }
 
-impl From<lex::LexErr> for CalcErr {
-    fn from(e: lex::LexErr) -> Self {
-        CalcErr::Lex(e)
+    #[test]
+    pub fn sigfig_rounds_to_significant_figures() {
+        assert_eq!(Ok(1200.0), eval("sigfig(1234.5, 2)"));
+        assert_eq!(Ok(1234.5), eval("sigfig(1234.5, 5)"));
+        assert_eq!(Ok(-1230.0), eval("sigfig(-1234.5, 3)"));
+        assert_eq!(Ok(0.0046), eval("sigfig(0.004567, 2)"));
+        assert_eq!(Ok(0.005), eval("sigfig(0.004567, 1)"));
+        assert_eq!(Ok(10.0), eval("sigfig(9.96, 2)"));
+        assert_eq!(Ok(0.0), eval("sigfig(0, 3)"));
+        assert_eq!(Ok(1.0 / 3.0), eval("sigfig(1/3, 20)"));
+        assert_eq!(Ok(1.2e-310), eval("sigfig(1.234e-310, 2)"));
+        assert_eq!(Ok(-5e-324), eval("sigfig(-4.9e-324, 1)"));
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 0..6,
+                msg: SIGFIG_DOMAIN
+            }),
+            eval("sigfig(12, 0)")
+        );
     }
-}
 
-type ExprResult = Result<Expr, CalcErr>;
+    #[test]
+    pub fn nested_functions() {
+        assert_eq!(3.0, eval("ln(exp(3))").unwrap());
+        assert_eq!(2.0, eval("sqrt(sqrt(16))").unwrap());
+        assert_eq!(1.0, eval("abs(cos(acos(-1)))").unwrap());
+    }
 
-impl Expr {
-    fn eval(self) -> f64 {
-        use Expr::*;
+    #[test]
+    pub fn functions_in_binary_expr() {
+        assert!((eval("sqrt(2)^2").unwrap() - 2.0).abs() < 1e-12);
+        assert_eq!(-(0.5f64.sin()), eval("-sin(0.5)").unwrap());
+        assert_eq!(7.0, eval("1 + sqrt(9) * 2").unwrap());
+        assert_eq!(10.0, eval("sqrt(4) * (abs(-3) + 2)").unwrap());
+    }
 
-        match self {
-            Num(x) => x,
-            Unary(Neg, x) => -x.eval(),
-            Binary(Add, x, y) => x.eval() + y.eval(),
-            Binary(Sub, x, y) | Binary(Neg, x, y) => x.eval() - y.eval(),
-            Binary(Mul, x, y) => x.eval() * y.eval(),
-            Binary(Div, x, y) => x.eval() / y.eval(),
-            Binary(Mod, x, y) => x.eval() % y.eval(),
-            Binary(Pow, x, y) => x.eval().powf(y.eval()),
-            Unary(_, x) => x.eval(),
-        }
+    #[test]
+    pub fn lerp() {
+        assert_eq!(5.0, eval("lerp(0, 10, 0.5)").unwrap());
+        assert_eq!(10.0, eval("lerp(10, 20, 0)").unwrap());
+        assert_eq!(20.0, eval("lerp(10, 20, 1)").unwrap());
+        assert_eq!(25.0, eval("lerp(10, 20, 1.5)").unwrap());
+        assert_eq!(-5.0, eval("lerp(0, 10, -0.5)").unwrap());
+        assert_eq!(4.0, eval("lerp(1 + 1, 2 * 3, sqrt(0.25))").unwrap());
     }
-}
 
-mod recursive_descent_parse {
-    use super::*;
-    use Expr::*;
-    type Lexer<'a> = Peekable<lex::Lexer<'a>>;
+    #[test]
+    pub fn sigmoid() {
+        assert_eq!(0.5, eval("sigmoid(0)").unwrap());
+        assert_eq!(1.0 / (1.0 + (-2f64).exp()), eval("sigmoid(2)").unwrap());
+        assert_eq!(1.0, eval("sigmoid(1000)").unwrap());
+        assert_eq!(0.0, eval("sigmoid(-1000)").unwrap());
+        assert!(eval("sigmoid(-700)").unwrap() > 0.0);
+        assert!((eval("sigmoid(3) + sigmoid(-3)").unwrap() - 1.0).abs() < 1e-15);
+    }
 
-    fn parse_complete_expr(input: &mut Lexer) -> ExprResult {
-        let expr = parse_expr(input)?;
-        match input.next() {
-            None => Ok(expr),
-            Some(x) => {
-                let (pos, _) = x?;
-                Err(CalcErr::Lex((pos, UNEXPECTED_TOKEN)))
-            }
+    #[test]
+    pub fn dist() {
+        assert_eq!(5.0, eval("dist(0, 0, 3, 4)").unwrap());
+        assert_eq!(0.0, eval("dist(1, 1, 1, 1)").unwrap());
+        assert_eq!(5.0, eval("dist(-1, -1, -4, -5)").unwrap());
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::spanning(
+                0..4,
+                "dist takes 4 arguments"
+            ))),
+            eval("dist(0, 0, 3)")
+        );
+    }
+
+    #[test]
+    pub fn wavg() {
+        assert_eq!(17.5, eval("wavg(10, 1, 20, 3)").unwrap());
+        assert_eq!(10.0, eval("wavg(10, 2)").unwrap());
+        assert_eq!(2.0, eval("wavg(1, 1, 2, 1, 3, 1)").unwrap());
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::spanning(
+                0..4,
+                "wavg takes pairs of a value and a weight"
+            ))),
+            eval("wavg(10, 1, 20)")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::spanning(
+                0..4,
+                "wavg takes pairs of a value and a weight"
+            ))),
+            eval("wavg(10)")
+        );
+        for weightless in ["wavg(1, 1, 2, -1)", "wavg(5, 0)"] {
+            assert_eq!(
+                Err(CalcErr::Math {
+                    span: 0..4,
+                    msg: WAVG_DOMAIN
+                }),
+                eval(weightless),
+                "{}",
+                weightless
+            );
         }
     }
 
-    fn parse_expr(input: &mut Lexer) -> ExprResult {
-        let mut expr = parse_term(input)?;
-        loop {
-            match input.peek() {
-                None => return Ok(expr),
-                Some(x) => match x {
-                    Ok((_, Plus)) => {
-                        input.next();
-                        expr = Binary(Add, Box::new(expr), Box::new(parse_term(input)?))
-                    }
-                    Ok((_, Dash)) => {
-                        input.next();
-                        expr = Binary(Sub, Box::new(expr), Box::new(parse_term(input)?))
-                    }
-                    _ => return Ok(expr),
-                },
+    #[test]
+    pub fn functions_of_several_arguments() {
+        assert_eq!(Ok(7.0), eval("max(3, 7, 2)"));
+        assert_eq!(Ok(2.0), eval("min(3, 7, 2)"));
+        assert_eq!(Ok(-1.0), eval("min(-1, 1)"));
+        assert_eq!(Ok(3.0), eval("log(2, 8)"));
+        assert_eq!(Ok(3.0), eval("log(10, 1000)"));
+        assert_eq!(Ok(3.0), eval("log(1000)"));
+        assert!((eval("log(3, 81)").unwrap() - 4.0).abs() < 1e-12);
+        assert_eq!(Ok(6.0), eval("gcd(12, 18)"));
+        assert_eq!(Ok(4.0), eval("gcd(-8, 12, 20)"));
+        assert_eq!(Ok(5.0), eval("gcd(0, 5)"));
+        assert_eq!(Ok(36.0), eval("lcm(12, 18)"));
+        assert_eq!(Ok(60.0), eval("lcm(3, 4, 5)"));
+        assert_eq!(Ok(0.0), eval("lcm(0, 5)"));
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 0..3,
+                msg: GCD_DOMAIN
+            }),
+            eval("gcd(2.5, 5)")
+        );
+    }
+
+    #[test]
+    pub fn arity_errors_name_the_function() {
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::spanning(
+                0..3,
+                "min takes 2 or more arguments"
+            ))),
+            eval("min(1)")
+        );
+        assert_eq!(
+            "log takes 1 argument, or 2 as log(base, x) at position 0",
+            eval("log(2, 8, 3)").unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    pub fn wrong_arg_count_is_rejected() {
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::spanning(
+                0..4,
+                "lerp takes 3 arguments"
+            ))),
+            eval("lerp(0, 10)")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::spanning(
+                2..6,
+                "sqrt takes 1 argument"
+            ))),
+            eval("1+sqrt(4, 9)")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(10, EXPECTED_OPERAND))),
+            eval("lerp(0, 1,, 2)")
+        );
+        assert_eq!(ran_out_at(10, INCOMPLETE_EXPR), eval("lerp(0, 1,"));
+    }
+
+    #[test]
+    pub fn assignment() {
+        let mut env = Context::new();
+        assert_eq!(48.0, exec_with("x = 4 * 12", &mut env).unwrap());
+        assert_eq!(49.0, exec_with("x + 1", &mut env).unwrap());
+        assert_eq!(2.0, exec_with("x = 2", &mut env).unwrap());
+        assert_eq!(2.0, exec_with("x", &mut env).unwrap());
+        assert_eq!(3.0, exec_with("x = x + 1", &mut env).unwrap());
+        assert_eq!(4.0, exec_with("y = x + 1", &mut env).unwrap());
+        assert_eq!(12.0, exec_with("x * y", &mut env).unwrap());
+    }
+
+    #[test]
+    pub fn eval_with_uses_the_variables_given() {
+        let ctx = Context::new().with("width", 80.0).with("margin", 4.0);
+        assert_eq!(Ok(164.0), eval_with("width * 2 + margin", &ctx));
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::spanning(8..14, UNKNOWN_IDENTIFIER))),
+            eval_with("width + height", &ctx)
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(6, EXPECTED_END))),
+            eval_with("width = 1", &ctx)
+        );
+        assert_eq!(eval("2 + 2"), eval_with("2 + 2", &Context::new()));
+    }
+
+    #[test]
+    pub fn names_fall_back_to_the_resolver() {
+        let mut ctx = Context::new().with("rate", 2.0);
+        ctx.set_resolver(|name| match name {
+            "rate" | "pi" | "sqrt" => Some(100.0),
+            "usd" => Some(1.5),
+            "bad" => Some(f64::NAN),
+            _ => None,
+        });
+        // functions, constants and variables come before the resolver
+        assert_eq!(Ok(2.0), eval_with("rate", &ctx));
+        assert_eq!(Ok(consts::PI), eval_with("pi", &ctx));
+        assert_eq!(Ok(3.0), eval_with("sqrt(9)", &ctx));
+        assert_eq!(
+            Err(CalcErr::Incomplete {
+                span: 4..5,
+                msg: EXPECTED_LPAREN
+            }),
+            eval_with("sqrt", &ctx)
+        );
+        assert_eq!(Ok(3.0), eval_with("usd * rate", &ctx));
+        // names it can't resolve are unknown, and ones it resolves to
+        // something that isn't a number are errors, both where the name is
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::spanning(6..9, UNKNOWN_IDENTIFIER))),
+            eval_with("usd + eur", &ctx)
+        );
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 4..7,
+                msg: NOT_FINITE
+            }),
+            eval_with("1 + bad", &ctx)
+        );
+        // what it gives is part of the expression, not a variable of it
+        let expr = parse_with_vars("usd * 2", &ctx).unwrap();
+        assert_eq!(Ok(3.0), expr.eval(&Context::new()));
+    }
+
+    #[test]
+    pub fn each_name_is_resolved_once_per_parse() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let asked = Arc::new(AtomicUsize::new(0));
+        let mut ctx = Context::new();
+        let counter = Arc::clone(&asked);
+        ctx.set_resolver(move |name| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            if name == "usd" {
+                Some(1.5)
+            } else {
+                None
             }
-        }
+        });
+        assert_eq!(Ok(6.0), eval_with("usd + usd * (usd + usd)", &ctx));
+        assert_eq!(1, asked.load(Ordering::Relaxed));
+        assert_eq!(Ok(3.0), eval_with("usd * 2", &ctx));
+        assert_eq!(2, asked.load(Ordering::Relaxed));
     }
 
-    fn parse_term(input: &mut Lexer) -> ExprResult {
-        let mut expr = parse_factor(input)?;
-        loop {
-            match input.peek() {
-                None => return Ok(expr),
-                Some(x) => match x {
-                    Ok((_, Star)) => {
-                        input.next();
-                        expr = Binary(Mul, Box::new(expr), Box::new(parse_factor(input)?))
-                    }
-                    Ok((_, Slash)) => {
-                        input.next();
-                        expr = Binary(Div, Box::new(expr), Box::new(parse_factor(input)?))
-                    }
-                    Ok((_, Percent)) => {
-                        input.next();
-                        expr = Binary(Mod, Box::new(expr), Box::new(parse_factor(input)?))
-                    }
-                    _ => return Ok(expr),
-                },
+    #[test]
+    pub fn variables_missing_when_evaluated_are_errors() {
+        let ctx = Context::new().with("width", 80.0).with("margin", 4.0);
+        let expr = parse_with_vars("width * 2 + margin", &ctx).unwrap();
+        assert_eq!(Ok(164.0), expr.eval(&ctx));
+        assert_eq!(
+            Ok(24.0),
+            expr.eval(&Context::new().with("width", 10.0).with("margin", 4.0))
+        );
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 0..5,
+                msg: UNKNOWN_VARIABLE
+            }),
+            expr.eval(&Context::new())
+        );
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 12..18,
+                msg: UNKNOWN_VARIABLE
+            }),
+            expr.eval(&Context::new().with("width", 10.0))
+        );
+    }
+
+    #[test]
+    pub fn undefined_variable_is_rejected() {
+        let mut env = Context::new();
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(4, UNKNOWN_IDENTIFIER))),
+            exec_with("1 + x", &mut env)
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(4, UNKNOWN_IDENTIFIER))),
+            exec_with("x = x + 1", &mut env)
+        );
+        assert!(env.is_empty());
+    }
+
+    #[test]
+    pub fn assignment_is_only_allowed_at_top_level() {
+        let mut env = Context::new();
+        env.set("x", 1.0);
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(7, EXPECTED_RPAREN))),
+            exec_with("1 + (x = 2)", &mut env)
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(6, EXPECTED_END))),
+            exec_with("x = 1 = 2", &mut env)
+        );
+        assert_eq!(ran_out_at(3, INCOMPLETE_EXPR), exec_with("y =", &mut env));
+        assert_eq!(1, env.len());
+        assert_eq!(Some(1.0), env.get("x"));
+    }
+
+    #[test]
+    pub fn names_with_digits_underscores_and_primes() {
+        let mut env = Context::new();
+        assert_eq!(2.0, exec_with("v_0 = 2", &mut env).unwrap());
+        assert_eq!(4.0, exec_with("v_0^2", &mut env).unwrap());
+
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(1, lex::PRIMES_DISABLED))),
+            exec_with("x' = 1", &mut env)
+        );
+        let options = Options {
+            primes: true,
+            ..Options::default()
+        };
+        let statement = parse_statement("x' = 1", &options, &env).unwrap();
+        assert_eq!(Ok(1.0), statement.exec(&mut env));
+        assert_eq!(
+            Ok(3.0),
+            parse_with("x' + v_0", &options, &env).unwrap().eval(&env)
+        );
+    }
+
+    #[test]
+    pub fn reserved_names_cannot_be_assigned() {
+        let eval = |input| exec_with(input, &mut Context::new());
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::spanning(0..2, RESERVED_NAME))),
+            eval("pi = 3")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::spanning(0..4, RESERVED_NAME))),
+            eval("sqrt = 3")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::spanning(0..3, RESERVED_NAME))),
+            eval("ans = 3")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(0, RESERVED_NAME))),
+            eval("_ = 3")
+        );
+    }
+
+    #[test]
+    pub fn constants() {
+        let close = |x: f64, y: f64| (x - y).abs() < 1e-12;
+        assert!(close(consts::PI, eval("pi").unwrap()));
+        assert!(close(consts::E, eval("e").unwrap()));
+        assert!(close(2.0 * consts::PI, eval("2*pi").unwrap()));
+        assert!(close(consts::E * consts::E, eval("e^2").unwrap()));
+        assert!(close(0.0, eval("sin(pi)").unwrap()));
+    }
+
+    #[test]
+    pub fn scientific_notation() {
+        assert_eq!(6.022e23, eval("6.022e23").unwrap());
+        assert_eq!(999.0, eval("1e3-1").unwrap());
+        assert_eq!(1001.0, eval("1e3+1").unwrap());
+        assert_eq!(0.999, eval("1-1E-3").unwrap());
+        assert_eq!(2000.0, eval("2*1e3").unwrap());
+        assert_eq!(consts::E * consts::E, eval("e^2").unwrap());
+        assert_eq!(2f64.powf(consts::E), eval("2^e").unwrap());
+        // without digits after it an `e` is a name, multiplying the number
+        assert_eq!(Ok(2.0 * consts::E), eval("2e"));
+        assert_eq!(Ok(2.0 * consts::E), eval("2exp(1)"));
+        let mut env = Context::new().with("x", 3.0).with("ex", 4.0);
+        assert_eq!(Ok(60.0), exec_with("2e1x", &mut env));
+        assert_eq!(Ok(8.0), exec_with("2ex", &mut env));
+        assert_eq!(Ok(1.0), exec_with("e1x = 1", &mut env));
+        assert_eq!(Ok(2.0), exec_with("2 e1x", &mut env));
+    }
+
+    #[test]
+    pub fn trig_functions_can_take_degrees() {
+        let degrees = Options {
+            angle: Angle::Degrees,
+            ..Options::default()
+        };
+        let eval_degrees =
+            |input| parse_with(input, &degrees, &Context::new())?.eval(&Context::new());
+        assert_eq!(Ok(1.0), eval_degrees("sin(90)"));
+        assert_eq!(Ok(1.0), eval("sin(pi/2)"));
+        assert!((eval_degrees("cos(60)").unwrap() - 0.5).abs() < 1e-12);
+        assert!((eval_degrees("tan(45)").unwrap() - 1.0).abs() < 1e-12);
+        assert_eq!(Ok(90.0), eval_degrees("asin(1)"));
+        assert_eq!(Ok(180.0), eval_degrees("acos(-1)"));
+        assert_eq!(Ok(45.0), eval_degrees("atan(1)"));
+        assert_eq!(Ok(2.0), eval_degrees("sqrt(4)"));
+    }
+
+    #[test]
+    pub fn physical_constants_are_opt_in() {
+        let mut env = Context::new();
+        let physics = Options {
+            physics: true,
+            ..Options::default()
+        };
+        let eval_physics =
+            |input, env: &Context| parse_with(input, &physics, env).unwrap().eval(env).unwrap();
+        assert!((eval_physics("c", &env) - 2.998e8).abs() < 1e5);
+        assert_eq!(6.022_140_76e23, eval_physics("N_A", &env));
+        assert_eq!(6.626_070_15e-34, eval_physics("h", &env));
+        assert!((eval_physics("h / (2 * pi) / hbar", &env) - 1.0).abs() < 1e-9);
+
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(0, UNKNOWN_IDENTIFIER))),
+            eval("c")
+        );
+        assert_eq!(3.0, exec_with("c = 3", &mut env).unwrap());
+        assert!(parse_statement("c = 3", &physics, &env).is_err());
+    }
+
+    #[test]
+    pub fn radix_prefixed_integers() {
+        assert_eq!(256.0, eval("0xff + 1").unwrap());
+        assert_eq!(12.0, eval("0b100 * 3").unwrap());
+        assert_eq!(8.0, eval("0o10").unwrap());
+        assert_eq!(-16.0, eval("-0x10").unwrap());
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(8, lex::INVALID_DIGIT))),
+            eval("1 + 0b102")
+        );
+    }
+
+    #[test]
+    pub fn unknown_identifier_is_rejected() {
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::spanning(4..7, UNKNOWN_IDENTIFIER))),
+            eval("2 * tau")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(0, UNKNOWN_IDENTIFIER))),
+            eval("x")
+        );
+    }
+
+    #[test]
+    pub fn unknown_function_is_rejected() {
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::spanning(2..5, UNKNOWN_FUNCTION))),
+            eval("1+foo(2)")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(5, EXPECTED_LPAREN))),
+            eval("sqrt 4")
+        );
+        assert_eq!(ran_out_at(4, EXPECTED_LPAREN), eval("sqrt"));
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(6, UNKNOWN_IDENTIFIER))),
+            eval("2 * (1a")
+        );
+    }
+
+    #[test]
+    pub fn implicit_multiplication() {
+        assert_eq!(14.0, eval("2(3+4)").unwrap());
+        assert_eq!(21.0, eval("(1+2)(3+4)").unwrap());
+        assert_eq!(2.0 * consts::PI, eval("2pi").unwrap());
+        assert_eq!(6.0, eval("2sqrt(9)").unwrap());
+        assert_eq!(18.0, eval("2(3)^2").unwrap());
+        assert_eq!(-6.0, eval("-2(3)").unwrap());
+
+        let mut env = Context::new();
+        exec_with("x = 4", &mut env).unwrap();
+        assert_eq!(8.0, exec_with("2x", &mut env).unwrap());
+        assert_eq!(32.0, exec_with("2x^2", &mut env).unwrap());
+        assert_eq!(12.0, exec_with("x(3)", &mut env).unwrap());
+    }
+
+    #[test]
+    pub fn implicit_multiplication_binds_tighter_than_div() {
+        let mut env = Context::new();
+        exec_with("x = 4", &mut env).unwrap();
+        assert_eq!(0.125, exec_with("1/2x", &mut env).unwrap());
+        assert_eq!(1.0, eval("6/2(1+2)").unwrap());
+        assert_eq!(
+            parse("1/(2*pi)").unwrap().eval(&env),
+            parse("1/2pi").unwrap().eval(&env)
+        );
+    }
+
+    #[test]
+    pub fn adjacent_numbers_are_not_multiplied() {
+        assert_eq!(Err(CalcErr::Lex(LexErr::new(2, EXPECTED_END))), eval("2 2"));
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(5, EXPECTED_END))),
+            eval("(1+2)3")
+        );
+    }
+
+    #[test]
+    pub fn long_sums_are_evaluated_without_recursion() {
+        let input = vec!["1"; 50_000].join("+");
+        let start = std::time::Instant::now();
+        assert_eq!(Ok(50_000.0), eval(&input));
+        assert_eq!(Ok(-49_998.0), eval(&input.replacen('+', "-", 49_999)));
+        assert_eq!(Ok(1.0), eval(&vec!["1"; 50_000].join("*")));
+        assert!(start.elapsed().as_secs() < 5);
+    }
+
+    #[test]
+    pub fn long_sums_are_cloned_compared_and_debugged_without_recursion() {
+        let sum = parse(&vec!["1"; 50_000].join("+")).unwrap();
+        let copy = sum.clone();
+        assert_eq!(sum, copy);
+        assert_ne!(sum, parse(&vec!["1"; 49_999].join("+")).unwrap());
+        let debug = format!("{:?}", copy);
+        assert_eq!(49_999, debug.matches("Binary(Add").count());
+        assert!(debug.ends_with("Num(1.0, 99998..99999))"));
+    }
+
+    #[test]
+    pub fn comparisons() {
+        assert_eq!(1.0, eval("3 > 2").unwrap());
+        assert_eq!(0.0, eval("3 < 2").unwrap());
+        assert_eq!(1.0, eval("1 == 1").unwrap());
+        assert_eq!(1.0, eval("2 <= 5").unwrap());
+        assert_eq!(1.0, eval("5 >= 5").unwrap());
+        assert_eq!(0.0, eval("1 != 1").unwrap());
+        assert_eq!(1.0, eval("2 + 2 == 4").unwrap());
+        assert_eq!(1.0, eval("2 * 3 > 2 ^ 2").unwrap());
+        assert_eq!(2.0, eval("(1 < 2) + (3 > 2)").unwrap());
+        assert_eq!(1.0, eval("sqrt(4 == 4)").unwrap());
+
+        let mut env = Context::new();
+        assert_eq!(0.0, exec_with("x = 1 > 2", &mut env).unwrap());
+        assert_eq!(1.0, exec_with("x == 0", &mut env).unwrap());
+    }
+
+    #[test]
+    pub fn comparisons_are_left_associative() {
+        assert_eq!(1.0, eval("3 > 2 > 0").unwrap());
+        assert_eq!(0.0, eval("3 > 2 > 1").unwrap());
+    }
+
+    #[test]
+    pub fn bitwise_operators() {
+        assert_eq!(Ok(8.0), eval("12 & 10"));
+        assert_eq!(Ok(14.0), eval("12 | 10"));
+        assert_eq!(Ok(5.0), eval("6 xor 3"));
+        assert_eq!(Ok(16.0), eval("1 << 4"));
+        assert_eq!(Ok(16.0), eval("256 >> 4"));
+        assert_eq!(Ok(-4.0), eval("-16 >> 2"));
+        assert_eq!(Ok(-1.0), eval("0 - 1 & -1"));
+        assert_eq!(Ok(i64::MIN as f64), eval("1 << 63"));
+    }
+
+    #[test]
+    pub fn bitwise_operators_bind_looser_than_comparisons() {
+        // (12 & 10) == 8 would be 1
+        assert_eq!(Ok(0.0), eval("12 & 10 == 8"));
+        assert_eq!(Ok(3.0), eval("1 | 2 xor 3 & 1 << 1 + 1"));
+        assert_eq!(Ok(4.0), eval("1 << 1 + 1"));
+        assert_eq!(Ok(0.0), eval("2 & 1 | 0"));
+    }
+
+    #[test]
+    pub fn bitwise_operators_need_integers() {
+        let math_at = |span, msg| Err(CalcErr::Math { span, msg });
+        assert_eq!(math_at(4..5, BITWISE_DOMAIN), eval("1.5 & 1"));
+        assert_eq!(math_at(2..3, BITWISE_DOMAIN), eval("1 | 2^64"));
+        assert_eq!(math_at(2..5, BITWISE_DOMAIN), eval("1 xor 0.5"));
+        assert_eq!(math_at(2..4, SHIFT_DOMAIN), eval("1 << 64"));
+        assert_eq!(math_at(2..4, SHIFT_DOMAIN), eval("1 >> -1"));
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::spanning(0..3, EXPECTED_OPERAND))),
+            eval("xor 1")
+        );
+    }
+
+    #[test]
+    pub fn bars_close_before_they_are_ors() {
+        assert_eq!(Ok(3.0), eval("|-1| | 2"));
+        assert_eq!(Ok(7.0), eval("|(3 | 4) - 14|"));
+        assert_eq!(Ok(3.0), eval("1 | |-2|"));
+        assert_eq!(Ok(2.0), eval("|max(2 | 0, 1)|"));
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(5, EXPECTED_END))),
+            eval("|1 | 2|")
+        );
+    }
+
+    #[test]
+    pub fn not_equal_is_not_factorial() {
+        assert_eq!(1.0, eval("3!=6").unwrap());
+        assert_eq!(1.0, eval("3! == 6").unwrap());
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(3, EXPECTED_END))),
+            eval("3! = 6")
+        );
+    }
+
+    #[test]
+    pub fn statements_are_split_on_semicolons() {
+        assert_eq!(
+            Ok(vec![(0, "1+1"), (4, " 2*3"), (9, " 4^2")]),
+            split_statements("1+1; 2*3; 4^2")
+        );
+        assert_eq!(Ok(vec![(0, "1+1")]), split_statements("1+1;"));
+        assert_eq!(Ok(vec![(0, "1+1 ")]), split_statements("1+1 ;  "));
+        assert_eq!(Ok(vec![(0, "")]), split_statements(""));
+        assert_eq!(Ok(vec![(0, "sqrt(4)")]), split_statements("sqrt(4)"));
+    }
+
+    #[test]
+    pub fn empty_statements_are_rejected() {
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(0, EMPTY_STATEMENT))),
+            split_statements(";")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(3, EMPTY_STATEMENT))),
+            split_statements("1; ;2")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(2, EMPTY_STATEMENT))),
+            split_statements("1;;;")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(4, lex::UNKNOWN_SYMBOL))),
+            split_statements("1; 2$")
+        );
+    }
+
+    #[test]
+    pub fn eval_all_evaluates_each_statement() {
+        assert_eq!(Ok(vec![2.0, 6.0, 16.0]), eval_all("1+1; 2*3; 4^2"));
+        assert_eq!(Ok(vec![2.0]), eval_all("1+1;"));
+        assert_eq!(Ok(vec![3.0, 6.0]), eval_all("x = 3; x * 2"));
+        assert_eq!(Err(CalcErr::Lex(LexErr::new(1, EXPECTED_END))), eval("1;2"));
+    }
+
+    fn with_xy() -> Context {
+        let mut env = Context::new();
+        env.set("x", 3.0);
+        env.set("y", -0.5);
+        env
+    }
+
+    // the tree with every span emptied, to compare trees parsed from
+    // different text
+    fn unspanned(expr: &Expr) -> Expr {
+        use Expr::*;
+
+        expr.rebuild(|mut node| {
+            match &mut node {
+                Unary(_, span, _)
+                | Binary(_, span, ..)
+                | Call(_, span, _)
+                | Var(_, span)
+                | Num(_, span) => *span = 0..0,
             }
+            node
+        })
+    }
+
+    #[test]
+    pub fn simplify_folds_constants_and_identities() {
+        let env = with_xy();
+        let parse = |input: &str| parse_with(input, &Options::default(), &env).unwrap();
+        let simplify = |input| unspanned(&parse(input).simplify());
+        assert_eq!(unspanned(&parse("x * 5")), simplify("x * (2 + 3)"));
+        assert_eq!(unspanned(&parse("6")), simplify("2 * 3"));
+        assert_eq!(unspanned(&parse("10")), simplify("sqrt(16) + 2^3 - 2"));
+        // a folded value has the span of what it was folded from
+        let folded = parse("x * (2 + 3)").simplify();
+        assert!(
+            matches!(&folded, Expr::Binary(Mul, _, _, y) if **y == Expr::Num(5.0, 7..8)),
+            "{:?}",
+            folded
+        );
+        for same_as_x in [
+            "x + 0", "0 + x", "x - 0", "x * 1", "1 * x", "x ^ 1", "--x", "-(-x)",
+        ] {
+            let simplified = parse(same_as_x).simplify();
+            assert_eq!(
+                unspanned(&parse("x")),
+                unspanned(&simplified),
+                "{}",
+                same_as_x
+            );
         }
+        // a variable can be set to infinity, where `x * 0` fails
+        assert_eq!(parse("x * 0"), parse("x * 0").simplify());
+        assert!(eval_over("x * 0", "x", &[f64::INFINITY]).is_err());
+        let sum = parse("(x - 0 * 2) + y * (4 - 3)").simplify();
+        assert!(
+            matches!(&sum, Expr::Binary(Add, _, x, y) if **x == parse(" x") && **y == parse(&format!("{:14}y", ""))),
+            "{:?}",
+            sum
+        );
+        // long chains are simplified without recursion, like they are evaluated
+        let long = parse(&("x".to_string() + &" + 1".repeat(100_000)));
+        assert_eq!(long.eval(&env), long.simplify().eval(&env));
+        // what fails is kept so that it fails in the same place
+        let failing = parse("x + 1/0");
+        assert_eq!(failing, failing.simplify());
+        assert_eq!(
+            failing.eval(&env),
+            parse("x + 1/(1 - 1)").simplify().eval(&env)
+        );
     }
 
-    fn parse_factor(input: &mut Lexer) -> ExprResult {
-        let mut expr = parse_primary(input)?;
-        loop {
-            match input.peek() {
-                None => return Ok(expr),
-                Some(Ok((_, Caret))) => {
-                    input.next();
-                    expr = Binary(Pow, Box::new(expr), Box::new(parse_factor(input)?))
+    impl Rng {
+        fn expr(&mut self, depth: usize) -> String {
+            if depth == 0 || self.below(4) == 0 {
+                return self
+                    .pick(&["0", "1", "2", "0.5", "x", "y", "pi"])
+                    .to_string();
+            }
+            let x = self.expr(depth - 1);
+            match self.below(5) {
+                0 => format!("-{}", x),
+                1 => format!("{}(({})!)", self.pick(&["", "-"]), x),
+                2 => format!("{}({})", self.pick(&["sqrt", "ln", "abs", "sin"]), x),
+                3 => format!("max({}, {})", x, self.expr(depth - 1)),
+                _ => {
+                    let op = self.pick(&["+", "-", "*", "/", "^", "%", "<", "&", "|", "xor", "<<"]);
+                    format!("({} {} {})", x, op, self.expr(depth - 1))
                 }
-                _ => return Ok(expr),
             }
         }
     }
 
-    fn parse_primary(input: &mut Lexer) -> ExprResult {
-        match input.next() {
-            None => Err(CalcErr::Incomplete),
-            Some(x) => match x? {
-                (_, Number(n)) => Ok(Num(n)),
-                (_, LParen) => parse_parenthesised(input),
-                (_, Dash) => Ok(Unary(Neg, Box::new(parse_factor(input)?))),
-                (pos, _) => Err(CalcErr::Lex((pos, UNEXPECTED_TOKEN))),
-            },
+    #[test]
+    pub fn simplify_evaluates_the_same() {
+        let env = with_xy();
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..5000 {
+            let input = rng.expr(5);
+            let expr = parse_with(&input, &Options::default(), &env).unwrap();
+            assert_eq!(expr.eval(&env), expr.simplify().eval(&env), "{}", input);
         }
     }
 
-    fn parse_parenthesised(input: &mut Lexer) -> ExprResult {
-        let expr = parse_expr(input)?;
-        if let Some(x) = input.next() {
-            if let (_, RParen) = x? {
-                return Ok(expr);
-            }
+    #[test]
+    pub fn expressions_print_with_only_the_parentheses_needed() {
+        let print = |input| parse(input).unwrap().to_string();
+        assert_eq!("2 * (3 + 4)", print("2*(3+4)"));
+        assert_eq!("2 * 3 + 4", print("(2*3)+4"));
+        assert_eq!("2^3^4", print("2^(3^4)"));
+        assert_eq!("(2^3)^4", print("(2^3)^4"));
+        assert_eq!("1 - (2 - 3)", print("1 - (2 - 3)"));
+        assert_eq!("1 - 2 - 3", print("(1 - 2) - 3"));
+        assert_eq!("-2^2", print("-2^2"));
+        assert_eq!("(-2)^2", print("(-2)^2"));
+        assert_eq!("2^-1", print("2^-1"));
+        assert_eq!("(-3)!", print("(-3)!"));
+        assert_eq!("-3!", print("-3!"));
+        assert_eq!("(1 + 2)!", print("(1+2)!"));
+        assert_eq!("1 / (2 * sqrt(4))", print("1/2sqrt(4)"));
+        assert_eq!("max(1, 2 + 3) * |4 - 5|", print("max(1, 2+3) * |4-5|"));
+        assert_eq!("7 mod 3 // 2 % 1", print("7 mod 3 // 2 % 1"));
+        assert_eq!("(1 < 2) + (3 >= 4)", print("(1 < 2) + (3 >= 4)"));
+        assert_eq!("-(1 + 2)", print("-(1 + 2)"));
+        assert_eq!("1 | 2 xor 3 & 4 << 5 < 6", print("1|2 xor 3&4<<5<6"));
+        assert_eq!("(1 | 2) & 3", print("(1 | 2) & 3"));
+        assert_eq!("|(1 | 2)| + |1 & 2|", print("|(1|2)| + |1&2|"));
+        let negative = Expr::Binary(
+            Pow,
+            0..1,
+            Box::new(Expr::Num(-2.0, 0..1)),
+            Box::new(Expr::Num(2.0, 0..1)),
+        );
+        assert_eq!("(-2)^2", negative.to_string());
+        let num = |x| Box::new(Expr::Num(x, 0..1));
+        let negated = Expr::Unary(Neg, 0..1, num(-2.0));
+        assert_eq!("-(-2)", negated.to_string());
+        let difference = Expr::Binary(Sub, 0..1, num(1.0), num(-2.0));
+        assert_eq!("1 - (-2)", difference.to_string());
+        assert_eq!(
+            "2 * (-0.5)!",
+            Expr::Binary(
+                Mul,
+                0..1,
+                num(2.0),
+                Box::new(Expr::Unary(Fact, 0..1, num(-0.5)))
+            )
+            .to_string()
+        );
+        assert_eq!(
+            "max(-2, -3)",
+            Expr::Call("max".to_string(), 0..1, vec![*num(-2.0), *num(-3.0)]).to_string()
+        );
+        assert_eq!("-2", num(-2.0).to_string());
+        for expr in [negated, difference] {
+            assert_eq!(
+                expr.eval(&Context::new()),
+                parse(&expr.to_string()).unwrap().eval(&Context::new())
+            );
         }
-        Err(CalcErr::Incomplete)
     }
 
-    pub(super) fn parse(input: &str) -> ExprResult {
-        parse_complete_expr(&mut lex::Lexer::new(input).peekable())
+    #[test]
+    pub fn comparisons_are_found_at_the_top() {
+        assert!(parse("3 < 5").unwrap().is_comparison());
+        assert!(parse("1 + 1 == 2").unwrap().is_comparison());
+        assert!(!parse("1 + 0").unwrap().is_comparison());
+        assert!(!parse("(3 < 5) + 1").unwrap().is_comparison());
     }
-}
 
-pub fn eval(input: &str) -> Result<f64, CalcErr> {
-    Ok(recursive_descent_parse::parse(input)?.eval())
-}
+    #[test]
+    pub fn printing_round_trips() {
+        let env = with_xy();
+        let parse = |input: &str| parse_with(input, &Options::default(), &env);
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..5000 {
+            let input = rng.expr(5);
+            let expr = parse(&input).unwrap();
+            let printed = expr.to_string();
+            let reparsed = parse(&printed).unwrap();
+            assert_eq!(printed, reparsed.to_string(), "{}", input);
+            assert_eq!(unspanned(&expr), unspanned(&reparsed), "{}", input);
+            // simplifying can leave negative numbers, which read back negated
+            let simplified = expr.simplify();
+            let resimplified = parse(&simplified.to_string()).unwrap().simplify();
+            assert_eq!(
+                unspanned(&simplified),
+                unspanned(&resimplified),
+                "{}",
+                input
+            );
+            let (x, y) = (expr.eval(&env), reparsed.eval(&env));
+            assert_eq!(
+                x.map_err(|e| e.message()),
+                y.map_err(|e| e.message()),
+                "{}",
+                printed
+            );
+        }
+        // printed without recursion, like it is evaluated
+        let long = "x".to_string() + &" + 1".repeat(100_000);
+        assert_eq!(long, parse(&long).unwrap().to_string());
+    }
 
-#[cfg(test)]
-pub mod test {
-    use super::*;
+    #[test]
+    pub fn eval_over_binds_each_value() {
+        assert_eq!(
+            Ok(vec![0.0, 1.0, 4.0, 9.0]),
+            eval_over("x^2", "x", &[0.0, 1.0, 2.0, 3.0])
+        );
+        assert_eq!(Ok(vec![]), eval_over("2t + 1", "t", &[]));
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(4, UNKNOWN_IDENTIFIER))),
+            eval_over("x + y", "x", &[1.0])
+        );
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 1..2,
+                msg: DIVISION_BY_ZERO
+            }),
+            eval_over("1/x", "x", &[1.0, 0.0])
+        );
+    }
 
     #[test]
-    pub fn num_is_parsed() {
-        assert_eq!(1.0, eval("1.0").unwrap());
+    pub fn eval_each_keeps_going_past_errors() {
+        let results = eval_each("1/x", "x", &[1.0, 0.0, 2.0]).unwrap();
+        assert_eq!(Ok(1.0), results[0]);
+        assert!(results[1].is_err());
+        assert_eq!(Ok(0.5), results[2]);
+        assert!(eval_each("x +", "x", &[1.0]).is_err());
     }
 
     #[test]
-    pub fn mul() {
-        assert_eq!(15.0, eval("3 * 5").unwrap());
+    pub fn errors_are_positioned_in_the_whole_input() {
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(8, EXPECTED_OPERAND))),
+            eval_all("1+1; 2 */ 3")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(5, UNKNOWN_IDENTIFIER))),
+            eval_all("1+1; y")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(6, EXPECTED_OPERAND))),
+            eval_all("1; 2 *; 3")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(6, EXPECTED_OPERAND))),
+            eval_all("1; 2 *;")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(7, EXPECTED_LPAREN))),
+            eval_all("1; sqrt; 2")
+        );
+        assert_eq!(ran_out_at(6, INCOMPLETE_EXPR), eval_all("1; 2 *"));
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 4..5,
+                msg: DIVISION_BY_ZERO
+            }),
+            eval_all("1; 1/0")
+        );
     }
 
     #[test]
-    pub fn modulus() {
-        assert_eq!(1.0, eval("1 % 2").unwrap());
-        assert_eq!(0.0, eval("4 % 2").unwrap());
-        assert_eq!(2.0, eval("8 % 3").unwrap());
-        assert_eq!(4.0, eval("11 % 7").unwrap());
-        assert_eq!(2.0, eval("8 % 3").unwrap());
+    pub fn parse_all_returns_each_tree() {
+        assert_eq!(
+            Ok(vec![Expr::Num(1.0, 0..1), parse("  2 * 3").unwrap()]),
+            parse_all("1;2 * 3;")
+        );
+        assert_eq!(
+            Ok(vec![Expr::Binary(
+                Mul,
+                5..6,
+                Box::new(Expr::Num(2.0, 3..4)),
+                Box::new(Expr::Num(3.0, 7..8))
+            )]),
+            parse_all("   2 * 3")
+        );
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 7..12,
+                msg: NOT_FINITE
+            }),
+            parse_all("1; 2 ^ 1e400")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(3, EXPECTED_OPERAND))),
+            parse_all("1; )")
+        );
+    }
+
+    fn too_deep_at(pos: lex::TokenPosition) -> ExprResult {
+        Err(CalcErr::TooDeep { span: pos..pos + 1 })
     }
 
     #[test]
-    pub fn add() {
-        assert_eq!(9.0, eval("2 + 7").unwrap());
+    pub fn nesting_is_limited() {
+        let options = Options {
+            max_depth: 4,
+            ..Options::default()
+        };
+        let parse_nested = |input| parse_with(input, &options, &Context::new());
+        assert!(parse_nested("((((1))))").is_ok());
+        assert_eq!(too_deep_at(4), parse_nested("(((((1)))))"));
+        assert!(parse_nested("----1").is_ok());
+        assert_eq!(too_deep_at(4), parse_nested("-----1"));
+        assert!(parse_nested("2^2^2^2^2").is_ok());
+        assert_eq!(too_deep_at(9), parse_nested("2^2^2^2^2^2"));
+        assert!(parse_nested("sqrt(sqrt(((1))))").is_ok());
+        assert_eq!(too_deep_at(12), parse_nested("sqrt(sqrt((((1)))))"));
+        assert!(parse_nested("(1) + (2) * ((3)) - ((((4))))").is_ok());
     }
 
     #[test]
-    pub fn sub() {
-        assert_eq!(10.0, eval("11 - 1").unwrap());
+    pub fn default_nesting_limit() {
+        let nested = |depth| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        // with the stack of a main thread rather than a test thread, since an
+        // unoptimised build uses a lot of stack per level of parentheses
+        let limit = std::thread::Builder::new()
+            .stack_size(8 << 20)
+            .spawn(move || {
+                assert_eq!(Ok(1.0), eval(&nested(DEFAULT_MAX_DEPTH)));
+                assert_eq!(
+                    Err(CalcErr::TooDeep {
+                        span: DEFAULT_MAX_DEPTH..DEFAULT_MAX_DEPTH + 1
+                    }),
+                    eval(&nested(DEFAULT_MAX_DEPTH + 1))
+                );
+                // far more than would fit on the stack if it weren't stopped
+                let pasted = "(".repeat(100_000) + "1";
+                assert!(matches!(eval(&pasted), Err(CalcErr::TooDeep { .. })));
+            })
+            .unwrap();
+        limit.join().unwrap();
     }
 
     #[test]
-    pub fn pow() {
-        assert_eq!(25.0, eval("5^2").unwrap());
-        assert_eq!(3.0, eval("9^0.5").unwrap());
+    pub fn the_deepest_nesting_allowed_fits_the_stack() {
+        let options = Options {
+            max_depth: DEPTH_LIMIT,
+            ..Options::default()
+        };
+        let nested = format!("{}1{}", "(".repeat(DEPTH_LIMIT), ")".repeat(DEPTH_LIMIT));
+        let parsed = std::thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn(move || parse_with(&nested, &options, &Context::new()).is_ok())
+            .unwrap();
+        assert!(parsed.join().unwrap());
     }
 
     #[test]
-    pub fn double_neg() {
-        assert_eq!(2.0, eval("1--1").unwrap());
+    pub fn powers_are_reduced_by_a_modulus() {
+        let eval_mod = |input, m| parse(input)?.eval_mod(&Context::new(), m);
+        assert_eq!(Ok(6), eval_mod("17^3", 7));
+        assert_eq!(Ok(4), eval_mod("3^1000", 7));
+        assert_eq!(Ok(688_423_210), eval_mod("2^1000", 1_000_000_007));
+        assert_eq!(Ok(574_599_152), eval_mod("123456789^(10^18)", 998_244_353));
+        assert_eq!(Ok(11), eval_mod("2 + 3 * 4^50", 13));
+        assert_eq!(Ok(2), eval_mod("-5", 7));
+        assert_eq!(Ok(2), eval_mod("3 - 8", 7));
+        assert_eq!(Ok(0), eval_mod("5^0", 1));
+        // other operators are evaluated as usual before being reduced
+        assert_eq!(Ok(3), eval_mod("(20 / 2)^1", 7));
+        // numbers and variables that aren't integers are errors where they are
+        let half = Context::new().with("x", 0.5);
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 4..7,
+                msg: NOT_AN_INTEGER
+            }),
+            eval_mod("1 + 2.5", 7)
+        );
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 2..5,
+                msg: NOT_AN_INTEGER
+            }),
+            eval_mod("  2.5", 7)
+        );
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 4..5,
+                msg: NOT_AN_INTEGER
+            }),
+            parse_with_vars("2 * x", &half).and_then(|expr| expr.eval_mod(&half, 7))
+        );
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 1..2,
+                msg: NEGATIVE_EXPONENT
+            }),
+            eval_mod("2^-1", 7)
+        );
     }
 
     #[test]
-    pub fn chained_add() {
-        assert_eq!(3.0, eval("1+1+1").unwrap());
+    pub fn floor_division() {
+        assert_eq!(3.0, eval("7 // 2").unwrap());
+        assert_eq!(-4.0, eval("-7 // 2").unwrap());
+        assert_eq!(-4.0, eval("7 // -2").unwrap());
+        assert_eq!(3.0, eval("-7 // -2").unwrap());
+        assert_eq!(2.0, eval("7.5 // 3").unwrap());
+        assert_eq!(4.0, eval("8 // 2").unwrap());
+        assert_eq!(7.0, eval("1 + 7 // 2 * 2").unwrap());
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 2..4,
+                msg: DIVISION_BY_ZERO
+            }),
+            eval("7 // 0")
+        );
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(4, EXPECTED_OPERAND))),
+            eval("8 / / 2")
+        );
     }
 
     #[test]
-    pub fn paren_before_add() {
-        assert_eq!(0.3125, eval("(1+0.25)*0.25").unwrap());
-        assert_eq!(0.66, eval("1.2*(0.3+0.25)").unwrap());
+    pub fn division_by_zero_is_an_error() {
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 1..2,
+                msg: DIVISION_BY_ZERO
+            }),
+            eval("1/0")
+        );
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 2..3,
+                msg: DIVISION_BY_ZERO
+            }),
+            eval("5 % 0")
+        );
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 6..7,
+                msg: DIVISION_BY_ZERO
+            }),
+            eval("sqrt(1/0)")
+        );
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 2..3,
+                msg: DIVISION_BY_ZERO
+            }),
+            eval("-1/-0")
+        );
+
+        let mut env = Context::new();
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 5..6,
+                msg: DIVISION_BY_ZERO
+            }),
+            exec_with("x = 1/0", &mut env)
+        );
+        assert!(env.is_empty());
     }
 
     #[test]
-    pub fn paren_before_mul() {
-        assert_eq!(12.0, eval("2 * (5 + 1)").unwrap());
-        assert_eq!(11.0, eval("(2 * 5) + 1").unwrap());
+    pub fn non_finite_results_are_errors() {
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 2..3,
+                msg: NOT_FINITE
+            }),
+            eval("10^1000")
+        );
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 4..5,
+                msg: NOT_FINITE
+            }),
+            eval("1/10^1000")
+        );
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 1..2,
+                msg: NOT_FINITE
+            }),
+            eval("0^(-1)")
+        );
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 0..4,
+                msg: NOT_FINITE
+            }),
+            eval("sqrt(-1)")
+        );
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 0..4,
+                msg: NOT_FINITE
+            }),
+            eval("wavg(1e308, 10, 1, 1)")
+        );
+        assert_eq!(
+            Err(CalcErr::Math {
+                span: 0..5,
+                msg: NOT_FINITE
+            }),
+            eval("1e400")
+        );
     }
 
     #[test]
-    pub fn mul_before_add() {
-        assert_eq!(14.0, eval("4 * 3 + 2").unwrap());
-        assert_eq!(14.0, eval("2 + 4 * 3").unwrap());
+    pub fn math_errors_point_at_their_operator() {
+        let math = |span, msg| Err(CalcErr::Math { span, msg });
+        assert_eq!(math(6..7, NOT_FINITE), eval("1e308 (10)"));
+        assert_eq!(math(1..3, NOT_FINITE), eval("-ln(0)"));
+        assert_eq!(math(8..9, DIVISION_BY_ZERO), eval("(1 + 2) / (3 - 3)"));
+        assert_eq!(math(4..9, NOT_FINITE), eval("1 + 1e999"));
     }
 
     #[test]
-    pub fn neg_before_add() {
-        assert_eq!(0.0, eval("-5 + 5").unwrap());
-        assert_eq!(-10.0, eval("-(5 + 5)").unwrap());
+    pub fn large_finite_results_are_kept() {
+        assert_eq!(2f64.powi(1000), eval("2^1000").unwrap());
+        assert_eq!(f64::MAX, eval("1.7976931348623157e308").unwrap());
+        assert!(eval("170!").unwrap() > 7e306);
+    }
+
+    fn subexpr(input: &str, byte_offset: usize) -> Result<f64, CalcErr> {
+        eval_subexpr(input, byte_offset, &Options::default(), &Context::new())
     }
 
     #[test]
-    pub fn pow_before_all() {
-        assert_eq!(-25.0, eval("-5^2").unwrap());
-        assert_eq!(37.0, eval("6^2+1").unwrap());
-        assert_eq!(200.0, eval("2*10^2").unwrap());
-        assert_eq!(2.0, eval("2^2/2").unwrap());
+    pub fn subexpr_under_offset_is_evaluated() {
+        let input = "1 + (2 * 3) + 4";
+        assert_eq!(Ok(6.0), subexpr(input, 6));
+        assert_eq!(Ok(6.0), subexpr(input, 4));
+        assert_eq!(Ok(6.0), subexpr(input, 10));
+        assert_eq!(Ok(11.0), subexpr(input, 0));
+        assert_eq!(Ok(11.0), subexpr(input, 100));
     }
+
     #[test]
-    pub fn is_left_associative() {
-        assert_eq!(1.0, eval("5 * 2 % 3").unwrap());
-        assert_eq!(9.0, eval("6 / 2 * 3").unwrap())
+    pub fn innermost_subexpr_is_chosen() {
+        let input = "2 * (1 + (3 - 1)) ^ 2";
+        assert_eq!(Ok(2.0), subexpr(input, 12));
+        assert_eq!(Ok(3.0), subexpr(input, 5));
     }
 
     #[test]
-    pub fn unexpected_token_is_rejected() {
-        assert_eq!(Err(CalcErr::Lex((7, UNEXPECTED_TOKEN))), eval("1 - 5 */ 5"));
-        assert_eq!(Err(CalcErr::Lex((1, UNEXPECTED_TOKEN))), eval("2()"));
-        assert_eq!(Err(CalcErr::Lex((3, UNEXPECTED_TOKEN))), eval("2*()"));
+    pub fn calls_are_chosen_whole() {
+        assert_eq!(Ok(2.0), subexpr("sqrt(4) + 1", 5));
+        assert_eq!(Ok(2.0), subexpr("max(1, 2) + 3", 5));
+        assert_eq!(Ok(2.0), subexpr("max(1, 2) + 3", 0));
+        assert_eq!(Ok(5.0), subexpr("max(1, 2) + 3", 11));
+        assert_eq!(Ok(3.0), subexpr("1 + max(1, (1 + 2)) * 2", 13));
+        // a variable before parentheses multiplies them
+        let ctx = Context::new().with("x", 2.0);
+        let options = Options::default();
+        assert_eq!(Ok(7.0), eval_subexpr("x(3 + 4)", 3, &options, &ctx));
     }
 
     #[test]
-    pub fn incomplete_expr_is_identified() {
-        assert_eq!(Err(CalcErr::Incomplete), eval("2 * "));
-        assert_eq!(Err(CalcErr::Incomplete), eval("2 * ("));
-        assert_eq!(Err(CalcErr::Incomplete), eval("2 * (5+2"));
-        assert_eq!(Ok(14.0), eval("2 * (5+2)"));
+    pub fn subexprs_use_the_options_and_variables_given() {
+        let ctx = Context::new().with("x", 5.0);
+        let options = Options {
+            angle: Angle::Degrees,
+            ..Options::default()
+        };
+        assert_eq!(Ok(10.0), eval_subexpr("1 + (2 * x) + 4", 6, &options, &ctx));
+        assert_eq!(Ok(1.0), eval_subexpr("2 + sin(90)", 6, &options, &ctx));
+        assert_eq!(Ok(16.0), eval_subexpr("x + 11", 0, &options, &ctx));
     }
 
     #[test]
-    pub fn unknown_symbol_is_rejected() {
-        assert_eq!(Err(CalcErr::Lex((4, lex::UNKNOWN_SYMBOL))), eval("2 * &"));
-        assert_eq!(Err(CalcErr::Lex((6, lex::UNKNOWN_SYMBOL))), eval("2 * (1a"));
+    pub fn subexpr_errors_point_into_the_whole_input() {
+        assert_eq!(
+            Err(CalcErr::Lex(LexErr::new(9, UNKNOWN_IDENTIFIER))),
+            subexpr("1 + (2 * x) + 4", 6)
+        );
+        assert_eq!(ran_out_at(4, UNCLOSED_PAREN), subexpr("1 + (2 * 3", 6));
     }
 }
@@ -0,0 +1,159 @@
+//! Finds where an expression sampled over a range of its variable, as for
+//! `rcalc --table`, could be evaluated, so that a range that is partly outside
+//! of where the expression is defined can be trimmed to the part inside it
+//! with a note saying so, rather than being filled with errors.
+
+use std::ops::Range;
+
+use crate::format::format_result;
+use crate::parse::CalcErr;
+
+/// Which of the samples an expression could be evaluated at.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Region {
+    Everywhere,
+    /// Only at the samples in `range`, where `gaps` is whether it is still
+    /// undefined at some of those.
+    Partly {
+        range: Range<usize>,
+        gaps: bool,
+    },
+    Nowhere,
+}
+
+/// The region made up of the samples in `results` that have a value.
+pub fn defined_region(results: &[Result<f64, CalcErr>]) -> Region {
+    let first = match results.iter().position(Result::is_ok) {
+        Some(first) => first,
+        None => return Region::Nowhere,
+    };
+    let last = results
+        .iter()
+        .rposition(Result::is_ok)
+        .expect("there is a first");
+    let range = first..last + 1;
+    let gaps = results[range.clone()].iter().any(Result::is_err);
+    if range == (0..results.len()) && !gaps {
+        Region::Everywhere
+    } else {
+        Region::Partly { range, gaps }
+    }
+}
+
+impl Region {
+    /// What to tell someone about the samples of `var` at `values` that are
+    /// left out, or `None` when none of them are.
+    pub fn note(&self, var: &str, values: &[f64]) -> Option<String> {
+        let show = |i: usize| format_result(values[i], None);
+        let (range, gaps) = match self {
+            Region::Everywhere => return None,
+            Region::Nowhere => {
+                let last = values.len().checked_sub(1)?;
+                return Some(format!(
+                    "undefined for every {} from {} to {}",
+                    var,
+                    show(0),
+                    show(last)
+                ));
+            }
+            Region::Partly { range, gaps } => (range, *gaps),
+        };
+        let (first, last) = (range.start, range.end - 1);
+        let mut undefined = Vec::new();
+        if first > 0 {
+            undefined.push(format!("{} < {}", var, show(first)));
+        }
+        if last < values.len() - 1 {
+            undefined.push(format!("{} > {}", var, show(last)));
+        }
+        if gaps {
+            undefined.push(format!("some {} in {}..{}", var, show(first), show(last)));
+        }
+        let mut note = format!("undefined for {}", undefined.join(" and "));
+        if range.len() < values.len() {
+            note += &format!("; showing {}..{}", show(first), show(last));
+        }
+        Some(note)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::parse::eval_each;
+
+    fn region(input: &str, values: &[f64]) -> Region {
+        defined_region(&eval_each(input, "x", values).unwrap())
+    }
+
+    fn range(from: i32, to: i32) -> Vec<f64> {
+        (from..=to).map(f64::from).collect()
+    }
+
+    #[test]
+    pub fn fully_defined() {
+        let values = range(0, 10);
+        assert_eq!(Region::Everywhere, region("x^2 - 3", &values));
+        assert_eq!(Region::Everywhere, region("sqrt(x)", &values));
+        assert_eq!(None, Region::Everywhere.note("x", &values));
+    }
+
+    #[test]
+    pub fn partly_defined_at_the_ends() {
+        let values = range(0, 10);
+        let below = region("sqrt(x - 2)", &values);
+        assert_eq!(
+            Region::Partly {
+                range: 2..11,
+                gaps: false
+            },
+            below
+        );
+        assert_eq!(
+            Some("undefined for x < 2; showing 2..10".to_string()),
+            below.note("x", &values)
+        );
+
+        let both = region("sqrt(x - 2) + ln(8 - x)", &values);
+        assert_eq!(
+            Some("undefined for x < 2 and x > 7; showing 2..7".to_string()),
+            both.note("x", &values)
+        );
+    }
+
+    #[test]
+    pub fn partly_defined_in_between() {
+        let values = range(-2, 2);
+        let gap = region("1/x", &values);
+        assert_eq!(
+            Region::Partly {
+                range: 0..5,
+                gaps: true
+            },
+            gap
+        );
+        assert_eq!(
+            Some("undefined for some x in -2..2".to_string()),
+            gap.note("x", &values)
+        );
+
+        let values = range(-3, 3);
+        assert_eq!(
+            Some("undefined for x < -1 and some x in -1..3; showing -1..3".to_string()),
+            region("sqrt(x + 1) / x", &values).note("x", &values)
+        );
+    }
+
+    #[test]
+    pub fn nowhere_defined() {
+        let values = range(0, 10);
+        let nowhere = region("sqrt(-1 - x)", &values);
+        assert_eq!(Region::Nowhere, nowhere);
+        assert_eq!(
+            Some("undefined for every x from 0 to 10".to_string()),
+            nowhere.note("x", &values)
+        );
+        assert_eq!(Region::Nowhere, defined_region(&[]));
+        assert_eq!(None, Region::Nowhere.note("x", &[]));
+    }
+}
@@ -0,0 +1,167 @@
+//! Runs the table in `tests/precedence.txt` through `parse::eval`, which makes
+//! the fixture the reference for how the grammar is meant to behave. The same
+//! table is built into the binary for `rcalc --self-test`, so an installed
+//! build can be checked against it.
+
+use crate::format::format_result;
+use crate::parse::{eval, CalcErr};
+
+const CORPUS: &str = include_str!("../tests/precedence.txt");
+
+#[derive(Debug, PartialEq)]
+enum Expected<'a> {
+    Value(f64),
+    Error(usize, &'a str),
+    Incomplete,
+    Math(usize, &'a str),
+}
+
+fn parse_expected(expected: &str) -> Option<Expected<'_>> {
+    if expected == "incomplete" {
+        return Some(Expected::Incomplete);
+    }
+    if let Some(rest) = expected.strip_prefix("math ") {
+        let (pos, msg) = rest.split_at(rest.find(' ')?);
+        return Some(Expected::Math(pos.parse().ok()?, msg.trim()));
+    }
+    if let Some(rest) = expected.strip_prefix("error ") {
+        let (pos, msg) = rest.split_at(rest.find(' ')?);
+        return Some(Expected::Error(pos.parse().ok()?, msg.trim()));
+    }
+    expected.parse().ok().map(Expected::Value)
+}
+
+fn parse_row(line: &str) -> Option<(&str, &str)> {
+    let (input, expected) = line.split_at(line.rfind("=>")?);
+    Some((input.trim(), expected[2..].trim()))
+}
+
+// values only need to be within rounding error, but whatever is printed for
+// them has to read back as exactly the same value
+fn matches(expected: &Expected, actual: &Result<f64, CalcErr>) -> bool {
+    match (expected, actual) {
+        (Expected::Value(x), Ok(y)) => {
+            let close = x == y || (x - y).abs() <= 1e-12 * x.abs().max(y.abs());
+            close && format_result(*y, None).parse() == Ok(*y)
+        }
+        (Expected::Error(pos, msg), Err(CalcErr::Lex(e))) => {
+            *pos == e.span.start && *msg == e.message
+        }
+        (Expected::Incomplete, Err(CalcErr::Incomplete { .. })) => true,
+        (Expected::Math(pos, msg), Err(CalcErr::Math { span, msg: m })) => {
+            *pos == span.start && msg == m
+        }
+        _ => false,
+    }
+}
+
+/// How many rows of the built-in table passed, and a description of each row
+/// that didn't.
+#[derive(Debug, Default)]
+pub struct SelfTestReport {
+    pub passed: usize,
+    pub failures: Vec<String>,
+}
+
+impl SelfTestReport {
+    pub fn ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+fn run_table(table: &str) -> SelfTestReport {
+    let mut report = SelfTestReport::default();
+    for (i, line) in table.lines().enumerate() {
+        let line_no = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parsed = parse_row(line).and_then(|(input, exp)| Some((input, parse_expected(exp)?)));
+        match parsed {
+            None => report
+                .failures
+                .push(format!("line {}: malformed row '{}'", line_no, line)),
+            Some((input, expected)) => {
+                let actual = eval(input);
+                if matches(&expected, &actual) {
+                    report.passed += 1;
+                } else {
+                    report.failures.push(format!(
+                        "line {}: '{}' expected {:?}, got {:?}",
+                        line_no, input, expected, actual
+                    ));
+                }
+            }
+        }
+    }
+    report
+}
+
+/// Evaluates every row of the precedence table that rcalc is tested against.
+pub fn self_test() -> SelfTestReport {
+    run_table(CORPUS)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    pub fn precedence_corpus() {
+        let report = self_test();
+        assert!(
+            report.ok(),
+            "{} of {} corpus rows failed:\n{}",
+            report.failures.len(),
+            report.passed + report.failures.len(),
+            report.failures.join("\n")
+        );
+        assert!(report.passed > 400);
+    }
+
+    // parse, print, parse again and evaluate, which has to give the same
+    // result, and printing again has to give the same text
+    #[test]
+    pub fn corpus_round_trips_through_printing() {
+        let mut checked = 0;
+        for (input, _) in CORPUS.lines().filter_map(parse_row) {
+            let expr = match crate::parse::parse(input) {
+                Ok(expr) => expr,
+                Err(_) => continue,
+            };
+            let printed = expr.to_string();
+            let reparsed = crate::parse::parse(&printed)
+                .unwrap_or_else(|e| panic!("'{}' printed as '{}': {}", input, printed, e));
+            assert_eq!(printed, reparsed.to_string(), "'{}'", input);
+            let env = crate::parse::Context::new();
+            match (expr.eval(&env), reparsed.eval(&env)) {
+                (Ok(x), Ok(y)) => assert_eq!(x, y, "'{}' printed as '{}'", input, printed),
+                (Err(e), Err(f)) => assert_eq!(e.message(), f.message(), "'{}'", input),
+                (x, y) => panic!("'{}' gave {:?} but '{}' gave {:?}", input, x, printed, y),
+            }
+            checked += 1;
+        }
+        assert!(checked > 300, "{}", checked);
+    }
+
+    #[test]
+    pub fn wrong_expectations_are_reported() {
+        let table = "# comment\n\
+                     1 + 1 => 2\n\
+                     1 + 1 => 3\n\
+                     2 * => incomplete\n\
+                     1 / 0 => math 2 division by zero\n\
+                     1 ? 2\n";
+        let report = run_table(table);
+        assert_eq!(3, report.passed);
+        assert_eq!(
+            vec![
+                "line 3: '1 + 1' expected Value(3.0), got Ok(2.0)".to_string(),
+                "line 6: malformed row '1 ? 2'".to_string(),
+            ],
+            report.failures
+        );
+        assert!(!report.ok());
+    }
+}
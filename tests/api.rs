@@ -0,0 +1,108 @@
+use rcalc::parse::{self, Context, Expr, Operator};
+
+#[test]
+pub fn parse_exposes_the_tree() {
+    let expr = parse::parse("2 * (3 + 4)").unwrap();
+    match &expr {
+        Expr::Binary(Operator::Mul, span, x, y) => {
+            assert_eq!(2..3, *span);
+            assert_eq!(Expr::Num(2.0, 0..1), **x);
+            assert!(matches!(&**y, Expr::Binary(Operator::Add, span, _, _) if *span == (7..8)));
+        }
+        e => panic!("unexpected tree {:?}", e),
+    }
+    assert_eq!(Ok(14.0), expr.eval(&Context::new()));
+}
+
+#[test]
+pub fn eval_matches_parse_then_eval() {
+    for input in &["1 + 2 * 3", "-5^2", "sqrt(16) / 2", "3!"] {
+        let expr = parse::parse(input).unwrap();
+        assert_eq!(parse::eval(input), expr.eval(&Context::new()));
+    }
+}
+
+#[test]
+pub fn eval_with_uses_the_callers_variables() {
+    let mut ctx = Context::new().with("width", 80.0);
+    ctx.set("margin", 4.0);
+    assert_eq!(Ok(164.0), parse::eval_with("width * 2 + margin", &ctx));
+    let err = parse::eval_with("width + height", &ctx).unwrap_err();
+    assert_eq!(Some(8..14), err.span());
+    assert_eq!(Ok(3.0), parse::exec_with("x = 3", &mut ctx));
+    assert_eq!(Some(3.0), ctx.get("x"));
+}
+
+#[test]
+pub fn parse_errors_are_returned() {
+    assert_eq!(
+        Err(parse::CalcErr::Incomplete {
+            span: 3..4,
+            msg: parse::INCOMPLETE_EXPR
+        }),
+        parse::parse("2 *")
+    );
+    assert!(matches!(
+        parse::parse("2 * )"),
+        Err(parse::CalcErr::Lex(rcalc::lex::LexErr { span, .. })) if span == (4..5)
+    ));
+}
+
+#[test]
+pub fn errors_display_a_message_and_position() {
+    let message = |input| parse::eval(input).unwrap_err().to_string();
+    assert_eq!("expected an operand at position 4", message("2 * )"));
+    assert_eq!("division by zero at position 1", message("1/0"));
+    assert_eq!("incomplete expression at position 3", message("2 *"));
+}
+
+#[test]
+pub fn errors_work_with_question_mark() {
+    fn eval_boxed(input: &str) -> Result<f64, Box<dyn std::error::Error>> {
+        Ok(parse::eval(input)?)
+    }
+    assert_eq!(4.0, eval_boxed("2 + 2").unwrap());
+    assert_eq!(
+        "unknown symbol at position 2",
+        eval_boxed("1 $ 2").unwrap_err().to_string()
+    );
+}
+
+#[test]
+pub fn hand_built_trees_are_checked_when_evaluated() {
+    let num = |x| Box::new(Expr::Num(x, 0..1));
+    let message = |expr: Expr| expr.eval(&Context::new()).unwrap_err().message();
+    let err = Expr::Var("y".to_string(), 4..5).eval(&Context::new());
+    assert_eq!(
+        Err(parse::CalcErr::Math {
+            span: 4..5,
+            msg: parse::UNKNOWN_VARIABLE
+        }),
+        err
+    );
+    let call = |name: &str, args| Expr::Call(name.to_string(), 0..1, args);
+    assert_eq!(
+        "unknown function",
+        message(call("nosuch", vec![Expr::Num(1.0, 2..3)]))
+    );
+    assert_eq!("sqrt takes 1 argument", message(call("sqrt", vec![])));
+    assert_eq!(
+        "lerp takes 3 arguments",
+        message(call("lerp", vec![Expr::Num(1.0, 2..3)]))
+    );
+    assert_eq!(
+        parse::NOT_BINARY,
+        message(Expr::Binary(Operator::Fact, 0..1, num(1.0), num(2.0)))
+    );
+    assert_eq!(
+        parse::NOT_UNARY,
+        message(Expr::Unary(Operator::Add, 0..1, num(1.0)))
+    );
+    // and they still print
+    let fact = Expr::Binary(Operator::Fact, 0..1, num(1.0), num(2.0));
+    assert_eq!("1 ! 2", fact.to_string());
+    assert_eq!(
+        "+ 1",
+        Expr::Unary(Operator::Add, 0..1, num(1.0)).to_string()
+    );
+}
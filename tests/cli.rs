@@ -0,0 +1,430 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+fn rcalc(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rcalc"))
+        .args(args)
+        .output()
+        .expect("failed to run rcalc")
+}
+
+// a fresh history or variables file for each REPL session, so tests never
+// touch the real ones
+fn temp_file(kind: &str) -> PathBuf {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    std::env::temp_dir().join(format!(
+        "rcalc-{}-{}-{}",
+        kind,
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::SeqCst)
+    ))
+}
+
+fn rcalc_filter(args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rcalc"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to run rcalc");
+    // rcalc may exit without reading its input, say when the expression is
+    // rejected, which closes the pipe before everything is written
+    let _ = child.stdin.take().unwrap().write_all(input.as_bytes());
+    child.wait_with_output().expect("failed to wait for rcalc")
+}
+
+fn rcalc_stdin(input: &str) -> Output {
+    let (history, vars) = (temp_file("history"), temp_file("vars"));
+    let out = rcalc_session(input, &history, &vars);
+    let _ = std::fs::remove_file(history);
+    let _ = std::fs::remove_file(vars);
+    out
+}
+
+fn rcalc_session(input: &str, history: &Path, vars: &Path) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rcalc"))
+        .env("RCALC_HISTORY", history)
+        .env("RCALC_VARS", vars)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to run rcalc");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().expect("failed to wait for rcalc")
+}
+
+#[test]
+pub fn result_goes_to_stdout_and_error_to_stderr() {
+    let out = rcalc(&["1 + 1"]);
+    assert_eq!("2\n", String::from_utf8_lossy(&out.stdout));
+    assert!(out.stderr.is_empty());
+
+    let out = rcalc(&["1 +* 1"]);
+    assert!(out.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("expected an operand"));
+}
+
+#[test]
+pub fn piped_session_only_writes_results_to_stdout() {
+    let out = rcalc_stdin("1 + 1\n2 $ 3\n3 * 3\n");
+    assert_eq!("2\n9\n", String::from_utf8_lossy(&out.stdout));
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("Welcome to rcalc!"));
+    assert!(stderr.contains("unknown symbol"));
+}
+
+#[test]
+pub fn split_arguments_are_joined() {
+    let out = rcalc(&["2", "*", "3"]);
+    assert!(out.status.success());
+    assert_eq!("6\n", String::from_utf8_lossy(&out.stdout));
+}
+
+#[test]
+pub fn incomplete_expression_fails() {
+    let out = rcalc(&["2 * 3 +"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("incomplete expression"));
+
+    let out = rcalc(&["2 * (5+2"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr)
+        .contains("  2 * (5+2\n      ^ expected ')' to close the '(' opened here"));
+}
+
+#[test]
+pub fn invalid_expression_fails() {
+    let out = rcalc(&["2", "$", "3"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("unknown symbol"));
+}
+
+#[test]
+pub fn division_by_zero_fails() {
+    let out = rcalc(&["1 / 0"]);
+    assert!(!out.status.success());
+    assert!(out.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("  1 / 0\n    ^ division by zero"));
+}
+
+#[test]
+pub fn errors_underline_the_whole_token() {
+    let out = rcalc(&["2 * sqrt(1, 2)"]);
+    assert!(String::from_utf8_lossy(&out.stderr)
+        .contains("  2 * sqrt(1, 2)\n      ^^^^ sqrt takes 1 argument"));
+    let out = rcalc(&["1 +"]);
+    assert!(String::from_utf8_lossy(&out.stderr).contains("  1 +\n     ^ incomplete expression"));
+}
+
+#[test]
+pub fn carets_line_up_under_wide_characters() {
+    let out = rcalc(&["１ + 2"]);
+    assert!(String::from_utf8_lossy(&out.stderr).contains("  １ + 2\n  ^^ unknown symbol"));
+    let out = rcalc(&["2 × 3 ÷ )"]);
+    assert!(String::from_utf8_lossy(&out.stderr)
+        .contains("  2 × 3 ÷ )\n          ^ expected an operand"));
+}
+
+#[test]
+pub fn statements_print_in_order() {
+    let out = rcalc(&["1+1; 2*3; 4^2"]);
+    assert!(out.status.success());
+    assert_eq!("2\n6\n16\n", String::from_utf8_lossy(&out.stdout));
+
+    let out = rcalc_stdin("x = 3; x * 2\n");
+    assert_eq!("3\n6\n", String::from_utf8_lossy(&out.stdout));
+}
+
+#[test]
+pub fn history_persists_between_sessions() {
+    let (history, vars) = (temp_file("history"), temp_file("vars"));
+    assert!(!history.exists());
+    let out = rcalc_session("1 + 1\n2 * 3\n", &history, &vars);
+    assert!(out.status.success());
+    let saved = std::fs::read_to_string(&history).unwrap();
+    assert!(saved.lines().any(|line| line == "1 + 1"));
+    assert!(saved.lines().any(|line| line == "2 * 3"));
+
+    rcalc_session(":hex\n", &history, &vars);
+    let saved = std::fs::read_to_string(&history).unwrap();
+    std::fs::remove_file(&history).unwrap();
+    assert!(!vars.exists());
+    let entries: Vec<_> = saved
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .collect();
+    assert_eq!(vec!["1 + 1", "2 * 3", ":hex"], entries);
+}
+
+#[test]
+pub fn autosaved_variables_are_there_next_session() {
+    let (history, vars) = (temp_file("history"), temp_file("vars"));
+    let out = rcalc_session("x = 2.5\ny = -1/3\n:set autosave on\n", &history, &vars);
+    assert!(out.status.success());
+    assert!(vars.exists());
+
+    let out = rcalc_session("x * 2\ny * 3\n:set autosave off\n", &history, &vars);
+    assert_eq!("5\n-1\n", String::from_utf8_lossy(&out.stdout));
+    assert!(!vars.exists());
+    let out = rcalc_session("x\n", &history, &vars);
+    std::fs::remove_file(&history).unwrap();
+    assert!(String::from_utf8_lossy(&out.stderr).contains("unknown identifier"));
+}
+
+#[test]
+pub fn max_depth_flag_limits_nesting() {
+    let out = rcalc(&["--max-depth", "3", "(((1)))"]);
+    assert!(out.status.success());
+    assert_eq!("1\n", String::from_utf8_lossy(&out.stdout));
+
+    let out = rcalc(&["--max-depth", "3", "((((1))))"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("nested too deeply"));
+
+    let out = rcalc(&[&("(".repeat(100_000) + "1")]);
+    assert_eq!(Some(1), out.status.code());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("nested too deeply"));
+
+    let out = rcalc(&["--max-depth", "none", "1"]);
+    assert_eq!(Some(2), out.status.code());
+    let out = rcalc(&["--max-depth", "100000000", "1"]);
+    assert_eq!(Some(2), out.status.code());
+}
+
+#[test]
+pub fn the_deepest_max_depth_still_errors_cleanly() {
+    let nested = |depth| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+    let input = format!(
+        ":set max-depth 2048\n{}\n{}\n",
+        nested(2048),
+        nested(200_000)
+    );
+    let out = rcalc_stdin(&input);
+    assert_eq!("1\n", String::from_utf8_lossy(&out.stdout));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("nested too deeply"));
+    let out = rcalc(&["--max-depth", "2048", &nested(2048)]);
+    assert_eq!("1\n", String::from_utf8_lossy(&out.stdout));
+}
+
+#[test]
+pub fn precision_flag_rounds_results() {
+    let out = rcalc(&["--precision", "4", "1/3; 2"]);
+    assert_eq!("0.3333\n2.0000\n", String::from_utf8_lossy(&out.stdout));
+}
+
+const FIELDS: &str = include_str!("fields.txt");
+
+#[test]
+pub fn field_mode_rewrites_one_column() {
+    let out = rcalc_filter(&["--field", "3", "-e", "x * 2"], FIELDS);
+    assert!(out.status.success());
+    assert_eq!(
+        "apples 3 2\npears 5 1\nplums lots 4.5\ncherries 12 0.2\n",
+        String::from_utf8_lossy(&out.stdout)
+    );
+
+    let out = rcalc_filter(&["--field", "2", "--delimiter", ",", "x + 1"], "a,1,b\n");
+    assert_eq!("a,2,b\n", String::from_utf8_lossy(&out.stdout));
+}
+
+#[test]
+pub fn field_mode_error_policies() {
+    let out = rcalc_filter(&["--field", "2", "-e", "x * 2"], FIELDS);
+    assert!(out.status.success());
+    assert_eq!(
+        "apples 6 1.00\npears 10 0.50\nplums lots 2.25\ncherries 24 0.10\n",
+        String::from_utf8_lossy(&out.stdout)
+    );
+    assert!(String::from_utf8_lossy(&out.stderr).contains("line 3: field is not a number"));
+
+    let out = rcalc_filter(&["--field", "2", "--on-error", "blank", "x * 2"], FIELDS);
+    assert!(out.status.success());
+    assert_eq!(
+        "apples 6 1.00\npears 10 0.50\nplums  2.25\ncherries 24 0.10\n",
+        String::from_utf8_lossy(&out.stdout)
+    );
+
+    let out = rcalc_filter(&["--field", "2", "--on-error", "abort", "x * 2"], FIELDS);
+    assert!(!out.status.success());
+    assert_eq!(
+        "apples 6 1.00\npears 10 0.50\n",
+        String::from_utf8_lossy(&out.stdout)
+    );
+}
+
+#[test]
+pub fn field_mode_needs_an_expression() {
+    let out = rcalc(&["--field", "2"]);
+    assert_eq!(Some(2), out.status.code());
+    let out = rcalc(&["--on-error", "blank", "1"]);
+    assert_eq!(Some(2), out.status.code());
+    let out = rcalc_filter(&["--field", "1", "-e", "x +"], "1\n");
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("incomplete expression"));
+}
+
+#[test]
+pub fn words_command_says_the_result() {
+    let out = rcalc_stdin(":words 6 * 7\n:words -1e6\n:words 1/3\n");
+    assert_eq!(
+        "forty-two\nminus one million\n",
+        String::from_utf8_lossy(&out.stdout)
+    );
+    assert!(String::from_utf8_lossy(&out.stderr).contains("too many decimal places"));
+}
+
+#[test]
+pub fn hexfloat_and_bits_show_the_representation() {
+    let out = rcalc_stdin(":hexfloat 3.14\n0x1.91eb851eb851fp+1 == 3.14\n:bits -0\n");
+    assert_eq!(
+        "0x1.91eb851eb851fp+1\n1\n1 00000000000 0000000000000000000000000000000000000000000000000000\n",
+        String::from_utf8_lossy(&out.stdout)
+    );
+}
+
+#[test]
+pub fn mod_command_reduces_powers() {
+    let out = rcalc_stdin(":mod 17^3 7\n:mod 2^1000 1e9+7\n:mod 2.5 7\n:mod 2 0\n");
+    assert_eq!("6\n688423210\n", String::from_utf8_lossy(&out.stdout));
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(
+        stderr.contains("only integers can be reduced"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("modulus must be a positive integer"));
+}
+
+#[test]
+pub fn listings_use_the_display_settings() {
+    let out = rcalc_stdin(":set si on\nx = 1500\n:vars\n:find x\n:mod 1500 10000\n");
+    assert_eq!(
+        "1.5k\nx = 1.5k\nx = 1500 = 1.5k\n1.5k\n",
+        String::from_utf8_lossy(&out.stdout)
+    );
+}
+
+#[test]
+pub fn bool_display_names_comparison_results() {
+    let out = rcalc_stdin("3 < 5\n:set bool-display on\n3 < 5\n2 == 3\n1 + 0\nb = 1 >= 2\n");
+    assert_eq!(
+        "1\ntrue\nfalse\n1\nfalse\n",
+        String::from_utf8_lossy(&out.stdout)
+    );
+}
+
+#[test]
+pub fn self_test_passes() {
+    let out = rcalc(&["--self-test"]);
+    assert!(out.status.success());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.ends_with(" passed, 0 failed\n"), "{}", stdout);
+}
+
+#[test]
+pub fn base_flag_shows_results_in_that_base() {
+    let out = rcalc(&["--base", "hex", "255"]);
+    assert_eq!("0xff\n", String::from_utf8_lossy(&out.stdout));
+    let out = rcalc(&["--base", "bin", "10"]);
+    assert_eq!("0b1010\n", String::from_utf8_lossy(&out.stdout));
+
+    let out = rcalc(&["--base", "hex", "2.5"]);
+    assert_eq!("2.5\n", String::from_utf8_lossy(&out.stdout));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("cannot display non-integer in base 16"));
+}
+
+#[test]
+pub fn reassign_warn_notes_the_old_value() {
+    let out = rcalc_stdin(":set reassign warn\nx = 1\nx = 2\n");
+    assert_eq!("1\n2\n", String::from_utf8_lossy(&out.stdout));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("note: 'x' was 1"));
+}
+
+#[test]
+pub fn table_prints_the_expression_over_a_range() {
+    let table = |args: &[&str]| {
+        let mut all = vec!["--table"];
+        all.extend_from_slice(args);
+        rcalc(&all)
+    };
+    let out = table(&[
+        "x^2", "--var", "x", "--from", "0", "--to", "3", "--step", "1",
+    ]);
+    assert!(out.status.success());
+    assert_eq!(
+        "0\t0\n1\t1\n2\t4\n3\t9\n",
+        String::from_utf8_lossy(&out.stdout)
+    );
+
+    let out = table(&[
+        "2t", "--var", "t", "--from", "1", "--to", "2", "--step", "0.5",
+    ]);
+    assert_eq!("1\t2\n1.5\t3\n2\t4\n", String::from_utf8_lossy(&out.stdout));
+
+    // the rows don't pick up rounding errors from adding up the steps
+    let out = table(&[
+        "x", "--var", "x", "--from", "0", "--to", "0.5", "--step", "0.1",
+    ]);
+    assert_eq!(
+        "0\t0\n0.1\t0.1\n0.2\t0.2\n0.3\t0.3\n0.4\t0.4\n0.5\t0.5\n",
+        String::from_utf8_lossy(&out.stdout)
+    );
+    let out = table(&["10^x", "--var", "x", "--from", "20", "--to", "21"]);
+    assert_eq!("20\t1e20\n21\t1e21\n", String::from_utf8_lossy(&out.stdout));
+
+    let out = table(&["x", "--var", "x", "--from", "0", "--to", "1", "--step", "0"]);
+    assert_eq!(Some(2), out.status.code());
+    let out = table(&["x", "--var", "x", "--from", "2", "--to", "1"]);
+    assert_eq!(Some(2), out.status.code());
+    let out = table(&["x + y", "--var", "x", "--from", "0", "--to", "1"]);
+    assert_eq!(Some(1), out.status.code());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("unknown identifier"));
+}
+
+#[test]
+pub fn table_leaves_out_where_the_expression_is_undefined() {
+    let table = |expr: &str, from: &str, to: &str| {
+        rcalc(&["--table", expr, "--var", "x", "--from", from, "--to", to])
+    };
+    let out = table("sqrt(x-2)", "0", "6");
+    assert!(out.status.success());
+    assert_eq!(
+        "2\t0\n3\t1\n4\t1.4142135623730951\n5\t1.7320508075688772\n6\t2\n",
+        String::from_utf8_lossy(&out.stdout)
+    );
+    assert_eq!(
+        "note: undefined for x < 2; showing 2..6\n",
+        String::from_utf8_lossy(&out.stderr)
+    );
+
+    let out = table("1/x", "-1", "1");
+    assert_eq!(
+        "-1\t-1\n0\tundefined\n1\t1\n",
+        String::from_utf8_lossy(&out.stdout)
+    );
+    assert!(String::from_utf8_lossy(&out.stderr).contains("undefined for some x in -1..1"));
+
+    let out = table("sqrt(-1-x)", "0", "3");
+    assert_eq!(Some(1), out.status.code());
+    assert_eq!("", String::from_utf8_lossy(&out.stdout));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("undefined for every x from 0 to 3"));
+}
+
+#[test]
+pub fn comments_are_ignored() {
+    let out = rcalc(&["2 + 2 # this is four"]);
+    assert_eq!("4\n", String::from_utf8_lossy(&out.stdout));
+
+    let out = rcalc_stdin("# prices\n3 * 4 # apples\n  # done\n");
+    assert_eq!("12\n", String::from_utf8_lossy(&out.stdout));
+    assert!(!String::from_utf8_lossy(&out.stderr).contains("error"));
+}